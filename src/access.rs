@@ -0,0 +1,132 @@
+use crate::config::Access;
+use crate::devices;
+use crate::handlers::auth::SESSION_FIELD_USER;
+use crate::model::{UserCompact, UserGroup};
+use crate::storage::ValkeyStorage;
+
+/// Why a user may not log in, worded for the user.
+#[derive(Debug)]
+pub enum Denial {
+    Blocked,
+    NotAllowed,
+    RegistrationClosed,
+    TooManyUsers(usize),
+    TooManySessions(usize),
+}
+
+impl Denial {
+    pub fn message(&self) -> String {
+        match self {
+            Denial::Blocked => "your osu! account may not use this relay".to_owned(),
+            Denial::NotAllowed => "this relay is only open to selected osu! users".to_owned(),
+            Denial::RegistrationClosed => {
+                "this relay doesn't accept new users at the moment".to_owned()
+            }
+            Denial::TooManyUsers(max) => {
+                format!("this relay has reached its limit of {} users", max)
+            }
+            Denial::TooManySessions(max) => format!(
+                "you already have {} sessions on this relay -- log out of one of them first",
+                max
+            ),
+        }
+    }
+}
+
+fn is_allowlisted(policy: &Access, user_id: u32, groups: &[UserGroup]) -> bool {
+    policy.allowed_user_ids.contains(&user_id)
+        || groups
+            .iter()
+            .any(|g| policy.allowed_groups.contains(&g.identifier))
+}
+
+/// Check the user against the allowlist and the blocklist.
+pub fn check_user(policy: &Access, user_id: u32, groups: &[UserGroup]) -> Result<(), Denial> {
+    if policy.blocked_user_ids.contains(&user_id) {
+        return Err(Denial::Blocked);
+    }
+    if policy.has_allowlist() && !is_allowlisted(policy, user_id, groups) {
+        return Err(Denial::NotAllowed);
+    }
+    Ok(())
+}
+
+/// Decide whether the user may log in with the session, taking the sign-up limits into account.
+pub async fn check_login(
+    policy: &Access,
+    storage: &ValkeyStorage,
+    user: &UserCompact,
+    session_id: &str,
+) -> eyre::Result<Result<(), Denial>> {
+    let groups = user.groups.as_deref().unwrap_or_default();
+    if let Err(denial) = check_user(policy, user.user_id, groups) {
+        return Ok(Err(denial));
+    }
+
+    // The limits are only there for everyone else.
+    if is_allowlisted(policy, user.user_id, groups) {
+        return Ok(Ok(()));
+    }
+    if let Some(max) = policy.max_sessions_per_user {
+        let other_sessions = devices::list(storage, user.user_id, session_id, None)
+            .await?
+            .iter()
+            .filter(|d| !d.current)
+            .count();
+        if other_sessions >= max {
+            return Ok(Err(Denial::TooManySessions(max)));
+        }
+    }
+
+    if devices::is_known_user(storage, user.user_id).await? {
+        return Ok(Ok(()));
+    }
+    if !policy.registration_open {
+        return Ok(Err(Denial::RegistrationClosed));
+    }
+    if let Some(max) = policy.max_users {
+        if devices::users(storage).await?.len() >= max {
+            return Ok(Err(Denial::TooManyUsers(max)));
+        }
+    }
+    Ok(Ok(()))
+}
+
+/// Group membership is only known from the profile cached in the session.
+async fn cached_groups(
+    storage: &ValkeyStorage,
+    session_id: &str,
+) -> eyre::Result<Option<Vec<UserGroup>>> {
+    Ok(storage
+        .get_json::<sessions::Data>(session_id)
+        .await?
+        .and_then(|data| data.get(SESSION_FIELD_USER).cloned())
+        .and_then(|v| serde_json::from_value::<UserCompact>(v).ok())
+        .map(|user| user.groups.unwrap_or_default()))
+}
+
+/// Log out all sessions of users who may no longer use the relay. Returns the number of purged sessions.
+pub async fn purge(policy: &Access, storage: &ValkeyStorage) -> eyre::Result<u64> {
+    if policy.blocked_user_ids.is_empty() && !policy.has_allowlist() {
+        return Ok(0);
+    }
+
+    let mut purged = 0;
+    for user_id in devices::users(storage).await? {
+        if check_user(policy, user_id, &[]).is_ok() {
+            continue;
+        }
+        for (device_id, session_id) in devices::sessions(storage, user_id).await? {
+            let denied = policy.blocked_user_ids.contains(&user_id)
+                || match cached_groups(storage, &session_id).await? {
+                    Some(groups) => check_user(policy, user_id, &groups).is_err(),
+                    // Without a profile, there's no telling whether the user is in one of the allowed groups.
+                    None => false,
+                };
+            if denied && devices::revoke(storage, user_id, &device_id).await? {
+                purged += 1;
+            }
+        }
+    }
+    Ok(purged)
+}
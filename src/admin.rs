@@ -0,0 +1,153 @@
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use sessions::Storage;
+
+use crate::api_keys;
+use crate::devices;
+use crate::handlers::auth::{
+    SESSION_FIELD_APP, SESSION_FIELD_DEVICE, SESSION_FIELD_USER, SESSION_FIELD_USER_ID,
+};
+use crate::model::{AdminOverview, DeviceInfo, SessionSummary};
+use crate::refresher::{self, UPDATE_THRESHOLD_SECS};
+use crate::secrets;
+use crate::storage::ValkeyStorage;
+
+/// How many of the sessions expiring soon are listed on the dashboard.
+pub const MAX_LISTED_SESSIONS: usize = 50;
+
+/// Strings in session data which are shown as they are, by their path; everything else could be a secret and is masked.
+const SAFE_FIELDS: [&str; 20] = [
+    "app",
+    "device.id",
+    "device.user_agent",
+    "handoff.return_to",
+    "login_attempts.app",
+    "login_attempts.scope",
+    "requested_app",
+    "requested_scopes",
+    "scopes",
+    "token.token_type",
+    "user.avatar_url",
+    "user.country.code",
+    "user.country.name",
+    "user.country_code",
+    "user.cover.custom_url",
+    "user.cover.url",
+    "user.groups.identifier",
+    "user.groups.name",
+    "user.groups.short_name",
+    "user.username",
+];
+
+fn field<T: DeserializeOwned>(data: &sessions::Data, name: &str) -> Option<T> {
+    data.get(name)
+        .and_then(|v| serde_json::from_value(v.clone()).ok())
+}
+
+fn summarize(id: &str, data: &sessions::Data, ttl: Option<i64>) -> SessionSummary {
+    SessionSummary {
+        fingerprint: secrets::fingerprint(id),
+        user: field(data, SESSION_FIELD_USER),
+        app: field(data, SESSION_FIELD_APP),
+        ttl,
+        token_expires_in: refresher::token_lifetime(data),
+    }
+}
+
+fn mask_secrets(path: &str, value: &mut Value) {
+    match value {
+        Value::String(secret) if !SAFE_FIELDS.contains(&path) => *secret = secrets::mask(secret),
+        Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                mask_secrets(&format!("{}.{}", path, k), v);
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|v| mask_secrets(path, v)),
+        _ => {}
+    }
+}
+
+pub async fn overview(storage: &ValkeyStorage) -> eyre::Result<AdminOverview> {
+    let keys = storage.session_keys().await?;
+    let sessions = storage.mget_json::<sessions::Data>(&keys).await?;
+    let total_sessions = keys.len();
+
+    let mut expiring = keys
+        .into_iter()
+        .zip(sessions)
+        .filter_map(|(key, data)| data.map(|data| (key, data)))
+        .filter(|(_, data)| {
+            refresher::token_lifetime(data).is_some_and(|exp| exp <= UPDATE_THRESHOLD_SECS as i64)
+        })
+        .collect::<Vec<_>>();
+    expiring.sort_by_key(|(_, data)| refresher::token_lifetime(data));
+    let expiring_soon = expiring.len();
+    expiring.truncate(MAX_LISTED_SESSIONS);
+
+    let listed_keys = expiring
+        .iter()
+        .map(|(key, _)| key.clone())
+        .collect::<Vec<_>>();
+    let ttls = storage.ttls(&listed_keys).await?;
+    let listed = expiring
+        .into_iter()
+        .zip(ttls)
+        .map(|((key, data), ttl)| summarize(&key, &data, ttl))
+        .collect();
+
+    Ok(AdminOverview {
+        total_sessions,
+        expiring_soon,
+        expiring: listed,
+        failures: refresher::recent_failures(storage).await?,
+    })
+}
+
+/// Session metadata, with tokens and other secrets masked.
+pub async fn inspect(
+    storage: &ValkeyStorage,
+    session_id: &str,
+) -> eyre::Result<Option<(SessionSummary, sessions::Data)>> {
+    let mut data = match storage.get_json::<sessions::Data>(session_id).await? {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+    let ttl = storage.ttl(session_id).await?;
+    let summary = summarize(session_id, &data, ttl);
+    for (k, v) in data.iter_mut() {
+        mask_secrets(k, v);
+    }
+    Ok(Some((summary, data)))
+}
+
+/// The session whose id has this fingerprint, so that links on the admin pages never carry the id itself.
+pub async fn find_session(
+    storage: &ValkeyStorage,
+    fingerprint: &str,
+) -> eyre::Result<Option<String>> {
+    Ok(storage
+        .session_keys()
+        .await?
+        .into_iter()
+        .find(|key| secrets::fingerprint(key) == fingerprint))
+}
+
+/// Log the session out, along with its API keys and its entry in the user's devices.
+pub async fn revoke(storage: &ValkeyStorage, session_id: &str) -> eyre::Result<bool> {
+    let data = match storage.get_json::<sessions::Data>(session_id).await? {
+        Some(data) => data,
+        None => return Ok(false),
+    };
+
+    if let (Some(user_id), Some(device)) = (
+        field::<u32>(&data, SESSION_FIELD_USER_ID),
+        field::<DeviceInfo>(&data, SESSION_FIELD_DEVICE),
+    ) {
+        if devices::revoke(storage, user_id, &device.id).await? {
+            return Ok(true);
+        }
+    }
+    api_keys::revoke_all(storage, session_id).await?;
+    storage.remove(session_id).await?;
+    Ok(true)
+}
@@ -0,0 +1,150 @@
+use chrono::Utc;
+use nanoid::nanoid;
+use sha2::{Digest, Sha256};
+
+use crate::model::{ApiKey, ApiKeyOwner, NewApiKey};
+use crate::storage::{ValkeyStorage, API_KEY_PREFIX, SESSION_API_KEYS_PREFIX};
+
+pub const DEFAULT_LABEL: &str = "unnamed client";
+pub const MAX_LABEL_LENGTH: usize = 64;
+
+const KEY_PREFIX: &str = "relay_";
+const KEY_LENGTH: usize = 48;
+const KEY_ID_LENGTH: usize = 12;
+
+/// Only the hash of a key is ever stored, so a leaked database doesn't leak working credentials.
+fn hash(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+fn owner_key(hash: &str) -> String {
+    format!("{}{}", API_KEY_PREFIX, hash)
+}
+
+fn session_keys_key(session_id: &str) -> String {
+    format!("{}{}", SESSION_API_KEYS_PREFIX, session_id)
+}
+
+pub fn sanitize_label(label: Option<&str>) -> String {
+    let label: String = label
+        .unwrap_or_default()
+        .trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_LABEL_LENGTH)
+        .collect();
+    if label.is_empty() {
+        DEFAULT_LABEL.to_owned()
+    } else {
+        label
+    }
+}
+
+/// Mint a new key for the session. The plaintext key is returned to the caller and never seen again.
+pub async fn create(
+    storage: &ValkeyStorage,
+    session_id: &str,
+    label: Option<&str>,
+) -> eyre::Result<NewApiKey> {
+    let key = format!("{}{}", KEY_PREFIX, nanoid!(KEY_LENGTH));
+    let record = ApiKey {
+        id: nanoid!(KEY_ID_LENGTH),
+        label: sanitize_label(label),
+        hash: hash(&key),
+        ctime: Utc::now().timestamp(),
+        last_used: None,
+    };
+
+    storage
+        .hset_json(&session_keys_key(session_id), &record.id, &record)
+        .await?;
+    storage
+        .set_json(
+            &owner_key(&record.hash),
+            &ApiKeyOwner {
+                session_id: session_id.to_owned(),
+                key_id: record.id.clone(),
+            },
+            None,
+        )
+        .await?;
+
+    Ok(NewApiKey {
+        id: record.id,
+        label: record.label,
+        key,
+    })
+}
+
+/// Look up whom a key was issued to, without counting it as used. Keys of sessions which are gone may still turn up.
+pub async fn owner(storage: &ValkeyStorage, key: &str) -> eyre::Result<Option<ApiKeyOwner>> {
+    storage
+        .get_json::<ApiKeyOwner>(&owner_key(&hash(key)))
+        .await
+}
+
+/// Resolve a key to the session it belongs to. Keys outliving their session are cleaned up on the spot.
+pub async fn authenticate(storage: &ValkeyStorage, key: &str) -> eyre::Result<Option<ApiKeyOwner>> {
+    let owner_key = owner_key(&hash(key));
+    let owner = match storage.get_json::<ApiKeyOwner>(&owner_key).await? {
+        Some(owner) => owner,
+        None => return Ok(None),
+    };
+
+    if !storage.exists(&owner.session_id).await? {
+        revoke_all(storage, &owner.session_id).await?;
+        storage.delete(&[owner_key.as_str()]).await?;
+        return Ok(None);
+    }
+
+    let keys_key = session_keys_key(&owner.session_id);
+    match storage
+        .hget_json::<ApiKey>(&keys_key, &owner.key_id)
+        .await?
+    {
+        Some(mut record) => {
+            record.last_used = Some(Utc::now().timestamp());
+            storage.hset_json(&keys_key, &record.id, &record).await?;
+            storage.touch_session(&owner.session_id).await?;
+            Ok(Some(owner))
+        }
+        None => {
+            storage.delete(&[owner_key.as_str()]).await?;
+            Ok(None)
+        }
+    }
+}
+
+pub async fn list(storage: &ValkeyStorage, session_id: &str) -> eyre::Result<Vec<ApiKey>> {
+    let mut keys = storage
+        .hvals_json::<ApiKey>(&session_keys_key(session_id))
+        .await?;
+    keys.sort_by_key(|k| k.ctime);
+    Ok(keys)
+}
+
+/// Revoke a single key of the session. Returns `false` if there was no such key.
+pub async fn revoke(storage: &ValkeyStorage, session_id: &str, key_id: &str) -> eyre::Result<bool> {
+    let keys_key = session_keys_key(session_id);
+    match storage.hget_json::<ApiKey>(&keys_key, key_id).await? {
+        Some(record) => {
+            storage.delete(&[owner_key(&record.hash).as_str()]).await?;
+            storage.hdel(&keys_key, key_id).await?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+pub async fn revoke_all(storage: &ValkeyStorage, session_id: &str) -> eyre::Result<()> {
+    let keys_key = session_keys_key(session_id);
+    let mut doomed: Vec<String> = list(storage, session_id)
+        .await?
+        .iter()
+        .map(|record| owner_key(&record.hash))
+        .collect();
+    doomed.push(keys_key);
+    storage
+        .delete(&doomed.iter().map(String::as_str).collect::<Vec<_>>())
+        .await
+}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+
+use crate::config::{self, App, Config};
+use crate::metrics;
+use crate::model::AppToken;
+use crate::storage::{ValkeyStorage, APP_TOKEN_PREFIX};
+
+/// The only scope osu! grants to tokens which aren't tied to a user.
+const APP_TOKEN_SCOPE: &str = "public";
+
+fn app_token_key(app: &App) -> String {
+    format!("{}{}", APP_TOKEN_PREFIX, app.name)
+}
+
+fn make_client_credentials_request(api: &config::API, app: &App) -> reqwest::Request {
+    reqwest::Client::new()
+        .post(api.token_url())
+        .form(&HashMap::from([
+            ("client_id", app.client_id.to_string()),
+            ("client_secret", app.client_secret.expose().to_owned()),
+            ("grant_type", "client_credentials".to_owned()),
+            ("scope", APP_TOKEN_SCOPE.to_owned()),
+        ]))
+        .header("Accept", "application/json")
+        .build()
+        .unwrap()
+}
+
+/// Obtain a new token from osu! and cache it for as long as it lives.
+async fn renew(config: &Config, storage: &ValkeyStorage, app: &App) -> eyre::Result<AppToken> {
+    let result = reqwest::Client::new()
+        .execute(make_client_credentials_request(&config.api, app))
+        .await;
+    metrics::observe_osu_api_response("client_credentials", &result);
+
+    let text = result?.error_for_status()?.text().await?;
+    let token: AppToken = serde_json::from_str(&text)?;
+    storage
+        .set_json(
+            &app_token_key(app),
+            &token,
+            Some(token.expires_in.try_into()?),
+        )
+        .await?;
+    Ok(token)
+}
+
+/// Serve the cached token of the app, falling back to obtaining one if the refresher hasn't done it yet.
+pub async fn get(config: &Config, storage: &ValkeyStorage, app: &App) -> eyre::Result<AppToken> {
+    match storage.get_json::<AppToken>(&app_token_key(app)).await? {
+        Some(token) => Ok(token),
+        None => renew(config, storage, app).await,
+    }
+}
+
+/// Renew tokens of all apps which are missing or about to expire. Returns the number of failures.
+pub async fn refresh_expiring(
+    config: &Config,
+    storage: &ValkeyStorage,
+    threshold_secs: i64,
+) -> u64 {
+    let mut failures = 0;
+    for app in config.api.apps.iter() {
+        let cached = match storage.get_json::<AppToken>(&app_token_key(app)).await {
+            Ok(cached) => cached,
+            Err(e) => {
+                log::error!(
+                    "Failed to load the app token of {} from Valkey: {}",
+                    app.name,
+                    e
+                );
+                failures += 1;
+                continue;
+            }
+        };
+        if cached.is_some_and(|token| token.lifetime() > threshold_secs) {
+            continue;
+        }
+
+        if let Err(e) = renew(config, storage, app).await {
+            log::warn!("Failed to renew the app token of {}: {}", app.name, e);
+            failures += 1;
+        }
+    }
+    failures
+}
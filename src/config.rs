@@ -1,27 +1,391 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
 use eyre::Result;
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+
+use crate::secrets::Secret;
+
+/// Environment variables starting with this override settings of the config file, e.g. `RELAY_SERVICE__BIND_PORT`.
+const ENV_PREFIX: &str = "RELAY_";
+const ENV_SEPARATOR: &str = "__";
+/// A setting with this suffix names a file to read the actual value from, e.g. `cookie_key_file`.
+const FILE_SUFFIX: &str = "_file";
+/// Settings of the only osu! API app, from before `api.apps`.
+const SINGLE_APP_FIELDS: [&str; 3] = ["client_id", "client_secret", "redirect_url"];
+/// Name of the app made from `SINGLE_APP_FIELDS`.
+const SINGLE_APP_NAME: &str = "default";
+
+/// How an environment variable is read into a setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// Taken as it is, so that secrets made of digits or colons stay text. May also be read from a file.
+    Text,
+    /// Read as YAML: numbers, booleans, lists or whole sections.
+    Yaml,
+}
+
+/// Settings which environment variables may override, with `*` standing for a list index or a key of a map.
+/// Keep in sync with the structs below.
+const SETTINGS: &[(&str, Kind)] = &[
+    ("api.apps", Kind::Yaml),
+    ("api.apps.*", Kind::Yaml),
+    ("api.apps.*.name", Kind::Text),
+    ("api.apps.*.client_id", Kind::Yaml),
+    ("api.apps.*.client_secret", Kind::Text),
+    ("api.apps.*.redirect_url", Kind::Text),
+    ("api.client_id", Kind::Yaml),
+    ("api.client_secret", Kind::Text),
+    ("api.redirect_url", Kind::Text),
+    ("api.scope", Kind::Yaml),
+    ("api.scope.*", Kind::Text),
+    ("api.allowed_scopes", Kind::Yaml),
+    ("api.allowed_scopes.*", Kind::Text),
+    ("api.base_url", Kind::Text),
+    ("api.auth_url", Kind::Text),
+    ("api.api_url", Kind::Text),
+    ("service.bind_host", Kind::Text),
+    ("service.bind_port", Kind::Yaml),
+    ("service.max_concurrent_requests", Kind::Yaml),
+    ("service.cookie_key", Kind::Text),
+    ("service.valkey", Kind::Yaml),
+    ("service.valkey.address", Kind::Text),
+    ("service.rate_limit", Kind::Yaml),
+    ("service.rate_limit.max_queued_requests", Kind::Yaml),
+    ("service.rate_limit.queue_timeout_ms", Kind::Yaml),
+    ("service.rate_limit.per_ip", Kind::Yaml),
+    ("service.rate_limit.per_ip.burst", Kind::Yaml),
+    ("service.rate_limit.per_ip.per_second", Kind::Yaml),
+    ("service.rate_limit.per_session", Kind::Yaml),
+    ("service.rate_limit.per_session.burst", Kind::Yaml),
+    ("service.rate_limit.per_session.per_second", Kind::Yaml),
+    ("service.trusted_proxies", Kind::Yaml),
+    ("service.trusted_proxies.*", Kind::Text),
+    ("service.forwarded_header", Kind::Text),
+    ("service.security_headers", Kind::Yaml),
+    ("service.security_headers.html", Kind::Yaml),
+    ("service.security_headers.html.*", Kind::Text),
+    ("service.security_headers.api", Kind::Yaml),
+    ("service.security_headers.api.*", Kind::Text),
+    ("service.theme_dir", Kind::Text),
+    ("admin.user_ids", Kind::Yaml),
+    ("admin.user_ids.*", Kind::Yaml),
+    ("access.allowed_user_ids", Kind::Yaml),
+    ("access.allowed_user_ids.*", Kind::Yaml),
+    ("access.allowed_groups", Kind::Yaml),
+    ("access.allowed_groups.*", Kind::Text),
+    ("access.blocked_user_ids", Kind::Yaml),
+    ("access.blocked_user_ids.*", Kind::Yaml),
+    ("access.max_sessions_per_user", Kind::Yaml),
+    ("access.max_users", Kind::Yaml),
+    ("access.registration_open", Kind::Yaml),
+    ("cookie.secure", Kind::Yaml),
+    ("cookie.same_site", Kind::Text),
+    ("cookie.domain", Kind::Text),
+    ("cookie.path", Kind::Text),
+    ("cookie.max_age_secs", Kind::Yaml),
+    ("cookie.session_lifetime_secs", Kind::Yaml),
+];
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub api: API,
     pub service: Service,
+
+    #[serde(default)]
+    pub admin: Admin,
+    #[serde(default)]
+    pub access: Access,
+    #[serde(default)]
+    pub cookie: Cookie,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct API {
+    pub apps: Vec<App>,
+    pub scope: Vec<String>,
+
+    #[serde(default)]
+    pub allowed_scopes: Vec<String>,
+
+    /// osu! web, e.g. the development server or a local mock.
+    #[serde(default = "default_base_url")]
+    pub base_url: String,
+    /// Where the OAuth endpoints live, if not on `base_url`.
+    pub auth_url: Option<String>,
+    /// Where the API lives, if not under `base_url`.
+    pub api_url: Option<String>,
+}
+
+fn default_base_url() -> String {
+    "https://osu.ppy.sh".to_owned()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct App {
+    pub name: String,
     pub client_id: u64,
-    pub client_secret: String,
+    pub client_secret: Secret,
     pub redirect_url: String,
-    pub scope: Vec<String>,
+}
+
+impl API {
+    /// Find an app by its name. The first one listed in config is the default.
+    pub fn app(&self, name: Option<&str>) -> Option<&App> {
+        match name {
+            Some(name) => self.apps.iter().find(|app| app.name == name),
+            None => self.apps.first(),
+        }
+    }
+
+    pub fn default_app(&self) -> &App {
+        &self.apps[0]
+    }
+
+    fn auth_base_url(&self) -> &str {
+        self.auth_url
+            .as_deref()
+            .unwrap_or(&self.base_url)
+            .trim_end_matches('/')
+    }
+
+    pub fn authorization_url(&self) -> String {
+        format!("{}/oauth/authorize", self.auth_base_url())
+    }
+
+    pub fn token_url(&self) -> String {
+        format!("{}/oauth/token", self.auth_base_url())
+    }
+
+    /// An API v2 endpoint, e.g. `api_endpoint("me")`.
+    pub fn api_endpoint(&self, path: &str) -> String {
+        match &self.api_url {
+            Some(api_url) => format!("{}/{}", api_url.trim_end_matches('/'), path),
+            None => format!("{}/api/v2/{}", self.base_url.trim_end_matches('/'), path),
+        }
+    }
+
+    pub fn user_profile_url(&self, user_id: u32) -> String {
+        format!("{}/users/{}", self.base_url.trim_end_matches('/'), user_id)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Service {
     pub bind_host: String,
     pub bind_port: u16,
-    pub max_concurrent_requests: i32,
-    pub cookie_key: Option<String>,
+    pub max_concurrent_requests: usize,
+    pub cookie_key: Option<Secret>,
     pub valkey: Valkey,
+
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+
+    /// Reverse proxies (as CIDRs or single addresses) whose `Forwarded` or `X-Forwarded-*` headers tell who the client is.
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    #[serde(default)]
+    pub forwarded_header: ForwardedHeader,
+
+    #[serde(default)]
+    pub security_headers: SecurityHeaders,
+
+    /// Directory with files overriding the built-in assets (`relay.css`, `theme.css`) and `footer.html`.
+    pub theme_dir: Option<String>,
+}
+
+/// The header which the trusted proxies set. The other one is ignored, since proxies tend to pass it on from clients as it is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+    /// `X-Forwarded-For` along with `X-Forwarded-Proto`, as set by nginx and most others.
+    #[default]
+    XForwardedFor,
+    /// `Forwarded` from RFC 7239.
+    Forwarded,
+}
+
+impl Service {
+    /// Parsed `trusted_proxies`, where a bare address stands for itself alone.
+    pub fn trusted_proxies(&self) -> Result<Vec<IpNet>> {
+        self.trusted_proxies
+            .iter()
+            .map(|proxy| {
+                proxy
+                    .parse::<IpNet>()
+                    .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| {
+                        eyre::eyre!(
+                            "service.trusted_proxies: {:?} is neither an IP address nor a CIDR",
+                            proxy
+                        )
+                    })
+            })
+            .collect()
+    }
+}
+
+/// What happens to requests beyond `max_concurrent_requests`, and how many requests a single client may make.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// Requests which may wait for a free slot. Any more are turned away right away.
+    #[serde(default)]
+    pub max_queued_requests: usize,
+    #[serde(default = "default_queue_timeout_ms")]
+    pub queue_timeout_ms: u64,
+
+    pub per_ip: Option<TokenBucket>,
+    /// Applies to browser sessions and API keys alike.
+    pub per_session: Option<TokenBucket>,
+}
+
+fn default_queue_timeout_ms() -> u64 {
+    5000
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            max_queued_requests: 0,
+            queue_timeout_ms: default_queue_timeout_ms(),
+            per_ip: None,
+            per_session: None,
+        }
+    }
+}
+
+/// Allows bursts of up to `burst` requests, refilled at `per_second` requests per second.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenBucket {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Admin {
+    /// osu! users allowed into /admin.
+    #[serde(default)]
+    pub user_ids: Vec<u32>,
+}
+
+impl Admin {
+    pub fn is_admin(&self, user_id: u32) -> bool {
+        self.user_ids.contains(&user_id)
+    }
+}
+
+/// Extra response headers on top of the built-in ones, per kind of response. An empty value removes a built-in header.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SecurityHeaders {
+    #[serde(default)]
+    pub html: BTreeMap<String, String>,
+    #[serde(default)]
+    pub api: BTreeMap<String, String>,
+}
+
+/// Who may log in. Allowlisted users skip the sign-up limits, but not the cap on their sessions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Access {
+    #[serde(default)]
+    pub allowed_user_ids: Vec<u32>,
+    /// osu! user group identifiers, e.g. `gmt` or `nat`.
+    #[serde(default)]
+    pub allowed_groups: Vec<String>,
+    #[serde(default)]
+    pub blocked_user_ids: Vec<u32>,
+
+    pub max_sessions_per_user: Option<usize>,
+    pub max_users: Option<usize>,
+    /// When closed, only users who already have sessions (or are allowlisted) may log in.
+    #[serde(default = "default_registration_open")]
+    pub registration_open: bool,
+}
+
+fn default_registration_open() -> bool {
+    true
+}
+
+impl Default for Access {
+    fn default() -> Self {
+        Self {
+            allowed_user_ids: Vec::new(),
+            allowed_groups: Vec::new(),
+            blocked_user_ids: Vec::new(),
+            max_sessions_per_user: None,
+            max_users: None,
+            registration_open: default_registration_open(),
+        }
+    }
+}
+
+impl Access {
+    pub fn has_allowlist(&self) -> bool {
+        !self.allowed_user_ids.is_empty() || !self.allowed_groups.is_empty()
+    }
+}
+
+/// Attributes of the session cookie, and how long sessions are kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cookie {
+    /// Only turn off when relay is served over plain HTTP, e.g. in development.
+    #[serde(default = "default_cookie_secure")]
+    pub secure: bool,
+    #[serde(default)]
+    pub same_site: SameSite,
+    pub domain: Option<String>,
+    #[serde(default = "default_cookie_path")]
+    pub path: String,
+    /// Max-Age of the cookie, if it should differ from the session lifetime. 0 keeps it until the browser is closed.
+    pub max_age_secs: Option<u64>,
+    /// How long a session is kept after it was last used, regardless of when its osu! token expires.
+    #[serde(default = "default_session_lifetime_secs")]
+    pub session_lifetime_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSite {
+    Strict,
+    #[default]
+    Lax,
+    None,
+}
+
+fn default_cookie_secure() -> bool {
+    true
+}
+
+fn default_cookie_path() -> String {
+    "/".to_owned()
+}
+
+fn default_session_lifetime_secs() -> u64 {
+    30 * 24 * 60 * 60
+}
+
+impl Default for Cookie {
+    fn default() -> Self {
+        Self {
+            secure: default_cookie_secure(),
+            same_site: SameSite::default(),
+            domain: None,
+            path: default_cookie_path(),
+            max_age_secs: None,
+            session_lifetime_secs: default_session_lifetime_secs(),
+        }
+    }
+}
+
+impl Cookie {
+    /// Max-Age of the cookie, or `None` for a cookie which only lasts until the browser is closed.
+    pub fn max_age_secs(&self) -> Option<u64> {
+        match self.max_age_secs.unwrap_or(self.session_lifetime_secs) {
+            0 => None,
+            secs => Some(secs),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -30,14 +394,529 @@ pub struct Valkey {
 }
 
 impl Config {
+    /// Read the config file, apply `RELAY_*` environment overrides on top of it, and then read secrets from `*_file` settings.
     pub fn load(path: &str) -> Result<Self> {
-        let data = std::fs::read_to_string(path)?;
-        serde_yaml::from_str::<Config>(&data).map_err(|e| e.into())
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| eyre::eyre!("failed to read the config from {}: {}", path, e))?;
+        let mut raw = serde_yaml::from_str::<Value>(&data)?;
+        apply_env_overrides(&mut raw, std::env::vars())?;
+        read_secret_files(&mut raw, "")?;
+        move_single_app(&mut raw)?;
+        let config = serde_yaml::from_value::<Config>(raw)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.api.apps.is_empty() {
+            eyre::bail!("at least one osu! API app must be listed under api.apps");
+        }
+        for (i, app) in self.api.apps.iter().enumerate() {
+            if self.api.apps[..i]
+                .iter()
+                .any(|other| other.name == app.name)
+            {
+                eyre::bail!("osu! API app {:?} is listed more than once", app.name);
+            }
+        }
+        for (field, url) in [
+            ("api.base_url", Some(&self.api.base_url)),
+            ("api.auth_url", self.api.auth_url.as_ref()),
+            ("api.api_url", self.api.api_url.as_ref()),
+        ] {
+            if let Some(url) = url {
+                if let Err(e) = reqwest::Url::parse(url) {
+                    eyre::bail!("{} is not a valid URL: {}", field, e);
+                }
+            }
+        }
+        self.service.trusted_proxies()?;
+        if self.service.max_concurrent_requests == 0 {
+            eyre::bail!("service.max_concurrent_requests must be positive");
+        }
+        for (field, bucket) in [
+            ("service.rate_limit.per_ip", &self.service.rate_limit.per_ip),
+            (
+                "service.rate_limit.per_session",
+                &self.service.rate_limit.per_session,
+            ),
+        ] {
+            if let Some(bucket) = bucket {
+                if bucket.burst == 0 || bucket.per_second <= 0.0 {
+                    eyre::bail!("{}: burst and per_second must be positive", field);
+                }
+            }
+        }
+        if !self.cookie.path.starts_with('/') {
+            eyre::bail!("cookie.path must start with a slash");
+        }
+        if self.cookie.domain.as_deref().is_some_and(str::is_empty) {
+            eyre::bail!("cookie.domain must not be empty -- leave it out instead");
+        }
+        if self.cookie.same_site == SameSite::None && !self.cookie.secure {
+            eyre::bail!("browsers reject cookies with cookie.same_site set to none unless cookie.secure is on");
+        }
+        if self.cookie.session_lifetime_secs == 0 {
+            eyre::bail!("cookie.session_lifetime_secs must be positive");
+        }
+        if let Some(theme_dir) = &self.service.theme_dir {
+            if !std::path::Path::new(theme_dir).is_dir() {
+                eyre::bail!("service.theme_dir {:?} is not a directory", theme_dir);
+            }
+        }
+        Ok(())
     }
 
-    pub fn save(&self, path: &str) -> Result<()> {
-        let data = serde_yaml::to_string(self)?;
-        std::fs::write(path, data)?;
+    /// Write a generated cookie key to the config file, leaving out everything that came from the environment or secret files.
+    pub fn save_cookie_key(path: &str, key: &Secret) -> Result<()> {
+        let mut raw = serde_yaml::from_str::<Value>(&std::fs::read_to_string(path)?)?;
+        let service = setting_mut(&mut raw, &["service"])?;
+        if let Value::Mapping(service) = service {
+            service.insert("cookie_key".into(), key.expose().into());
+        }
+        std::fs::write(path, serde_yaml::to_string(&raw)?)?;
         Ok(())
     }
 }
+
+/// The setting at `path`, which is created along with any missing sections. List items are addressed by their index.
+fn setting_mut<'a>(root: &'a mut Value, path: &[&str]) -> Result<&'a mut Value> {
+    let mut node = root;
+    for (depth, segment) in path.iter().enumerate() {
+        if node.is_null() {
+            *node = Value::Mapping(Mapping::new());
+        }
+        node = match node {
+            Value::Mapping(map) => map
+                .entry(Value::String((*segment).to_owned()))
+                .or_insert(Value::Null),
+            Value::Sequence(items) => {
+                let index = segment.parse::<usize>().map_err(|_| {
+                    eyre::eyre!(
+                        "{} is a list, so {:?} must be an index",
+                        path[..depth].join("."),
+                        segment
+                    )
+                })?;
+                if index == items.len() {
+                    items.push(Value::Null);
+                }
+                items.get_mut(index).ok_or_else(|| {
+                    eyre::eyre!("{} has no item #{}", path[..depth].join("."), index)
+                })?
+            }
+            _ => eyre::bail!("{} is not a section", path[..depth].join(".")),
+        };
+    }
+    Ok(node)
+}
+
+/// Counterpart of a setting with or without `FILE_SUFFIX`.
+fn file_counterpart(key: &str) -> String {
+    match key.strip_suffix(FILE_SUFFIX) {
+        Some(key) => key.to_owned(),
+        None => format!("{}{}", key, FILE_SUFFIX),
+    }
+}
+
+/// How the setting at `path` is read, or `None` if there is no such setting.
+fn setting_kind(path: &[&str]) -> Option<Kind> {
+    SETTINGS.iter().find_map(|(pattern, kind)| {
+        let pattern = pattern.split('.').collect::<Vec<_>>();
+        let matches = pattern.len() == path.len()
+            && pattern
+                .iter()
+                .zip(path)
+                .all(|(p, segment)| (*p == "*" && !segment.is_empty()) || p == segment);
+        matches.then_some(*kind)
+    })
+}
+
+/// Override settings with `RELAY_SECTION__FIELD` variables, which take precedence over both forms of the setting in the file.
+fn apply_env_overrides(
+    raw: &mut Value,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<()> {
+    let overrides = vars
+        .into_iter()
+        .filter_map(|(name, value)| {
+            let path = name.strip_prefix(ENV_PREFIX)?;
+            path.contains(ENV_SEPARATOR)
+                .then(|| (path.to_ascii_lowercase(), value))
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    for (path, value) in overrides.iter() {
+        if path.ends_with(FILE_SUFFIX) && overrides.contains_key(&file_counterpart(path)) {
+            eyre::bail!(
+                "set either {}{} or {}{}, not both",
+                ENV_PREFIX,
+                file_counterpart(path).to_ascii_uppercase(),
+                ENV_PREFIX,
+                path.to_ascii_uppercase()
+            );
+        }
+
+        let segments = path.split(ENV_SEPARATOR).collect::<Vec<_>>();
+        let (key, parents) = segments.split_last().unwrap();
+        // Only text can come from a file.
+        let kind = match key.strip_suffix(FILE_SUFFIX) {
+            Some(setting) => {
+                setting_kind(&[parents, &[setting]].concat()).filter(|kind| *kind == Kind::Text)
+            }
+            None => setting_kind(&segments),
+        };
+        let Some(kind) = kind else {
+            eyre::bail!(
+                "{}{} does not name a setting",
+                ENV_PREFIX,
+                path.to_ascii_uppercase()
+            );
+        };
+        let parent = setting_mut(raw, parents)?;
+        if parent.is_null() {
+            *parent = Value::Mapping(Mapping::new());
+        }
+        let Value::Mapping(section) = parent else {
+            eyre::bail!("{} is not a section", parents.join("."));
+        };
+        section.remove(file_counterpart(key).as_str());
+
+        let value = match kind {
+            Kind::Text => Value::String(value.clone()),
+            Kind::Yaml => serde_yaml::from_str::<Value>(value)
+                .map_err(|e| eyre::eyre!("{}{}: {}", ENV_PREFIX, path.to_ascii_uppercase(), e))?,
+        };
+        section.insert((*key).into(), value);
+    }
+    Ok(())
+}
+
+/// Turn the settings of a config from before `api.apps` into an app of its own, which is then the default one.
+fn move_single_app(raw: &mut Value) -> Result<()> {
+    let Some(Value::Mapping(api)) = raw.get_mut("api") else {
+        return Ok(());
+    };
+    let present = SINGLE_APP_FIELDS
+        .iter()
+        .filter(|field| api.contains_key(**field))
+        .collect::<Vec<_>>();
+    if present.is_empty() {
+        return Ok(());
+    }
+    if api.contains_key("apps") || present.len() < SINGLE_APP_FIELDS.len() {
+        eyre::bail!(
+            "api.{} must be moved into an entry of api.apps, along with a name (see config.template.yaml)",
+            present
+                .iter()
+                .map(|field| field.to_string())
+                .collect::<Vec<_>>()
+                .join(", api.")
+        );
+    }
+
+    log::warn!(
+        "The osu! API app is set up directly under api, which is deprecated -- move it into api.apps"
+    );
+    let mut app = Mapping::new();
+    app.insert("name".into(), SINGLE_APP_NAME.into());
+    for field in SINGLE_APP_FIELDS {
+        app.insert(field.into(), api.remove(field).unwrap());
+    }
+    api.insert("apps".into(), Value::Sequence(vec![Value::Mapping(app)]));
+    Ok(())
+}
+
+/// Replace every `*_file` setting with the contents of the file it names.
+fn read_secret_files(node: &mut Value, path: &str) -> Result<()> {
+    let join = |key: &str| match path {
+        "" => key.to_owned(),
+        _ => format!("{}.{}", path, key),
+    };
+    match node {
+        Value::Mapping(map) => {
+            let files = map
+                .iter()
+                .filter_map(|(k, v)| {
+                    Some((k.as_str()?.strip_suffix(FILE_SUFFIX)?.to_owned(), v.clone()))
+                })
+                .collect::<Vec<_>>();
+            for (key, file) in files {
+                if map.contains_key(key.as_str()) {
+                    eyre::bail!(
+                        "set either {} or {}{}, not both",
+                        join(&key),
+                        join(&key),
+                        FILE_SUFFIX
+                    );
+                }
+                let Value::String(file) = file else {
+                    eyre::bail!("{}{} must be a path", join(&key), FILE_SUFFIX);
+                };
+                let contents = std::fs::read_to_string(&file).map_err(|e| {
+                    eyre::eyre!(
+                        "failed to read {}{} from {}: {}",
+                        join(&key),
+                        FILE_SUFFIX,
+                        file,
+                        e
+                    )
+                })?;
+                map.remove(format!("{}{}", key, FILE_SUFFIX).as_str());
+                map.insert(key.into(), contents.trim_end_matches(['\r', '\n']).into());
+            }
+            for (key, value) in map.iter_mut() {
+                if let Some(key) = key.as_str() {
+                    read_secret_files(value, &join(key))?;
+                }
+            }
+        }
+        Value::Sequence(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                read_secret_files(item, &join(&i.to_string()))?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaml(s: &str) -> Value {
+        serde_yaml::from_str(s).unwrap()
+    }
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn overridden(file: &str, pairs: &[(&str, &str)]) -> Result<Value> {
+        let mut raw = yaml(file);
+        apply_env_overrides(&mut raw, vars(pairs))?;
+        Ok(raw)
+    }
+
+    /// A file in the temp directory, named after the test so that tests running at once don't share it.
+    fn secret_file(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("relay-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn env_text_stays_text() {
+        let raw = overridden(
+            "api:\n  apps:\n    - name: a\n",
+            &[
+                ("RELAY_API__APPS__0__CLIENT_SECRET", "0123456789"),
+                ("RELAY_API__APPS__1__CLIENT_SECRET", "key: value"),
+                ("RELAY_SERVICE__COOKIE_KEY", "true"),
+                ("RELAY_COOKIE__DOMAIN", "~"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(raw["api"]["apps"][0]["client_secret"], yaml("'0123456789'"));
+        assert_eq!(raw["api"]["apps"][1]["client_secret"], yaml("'key: value'"));
+        assert_eq!(raw["service"]["cookie_key"], yaml("'true'"));
+        assert_eq!(raw["cookie"]["domain"], yaml("'~'"));
+    }
+
+    #[test]
+    fn env_other_settings_are_yaml() {
+        let raw = overridden(
+            "service:\n  bind_port: 80\n",
+            &[
+                ("RELAY_SERVICE__BIND_PORT", "8080"),
+                ("RELAY_ADMIN__USER_IDS", "[2, 3]"),
+                ("RELAY_ACCESS__REGISTRATION_OPEN", "false"),
+                (
+                    "RELAY_SERVICE__RATE_LIMIT__PER_IP",
+                    "{burst: 10, per_second: 0.5}",
+                ),
+            ],
+        )
+        .unwrap();
+        assert_eq!(raw["service"]["bind_port"], yaml("8080"));
+        assert_eq!(raw["admin"]["user_ids"], yaml("[2, 3]"));
+        assert_eq!(raw["access"]["registration_open"], yaml("false"));
+        assert_eq!(
+            raw["service"]["rate_limit"]["per_ip"],
+            yaml("{burst: 10, per_second: 0.5}")
+        );
+
+        let err = overridden("", &[("RELAY_SERVICE__BIND_PORT", "[8080")]).unwrap_err();
+        assert!(err.to_string().starts_with("RELAY_SERVICE__BIND_PORT: "));
+    }
+
+    #[test]
+    fn env_replaces_both_variants_from_the_file() {
+        let raw = overridden(
+            "service:\n  cookie_key_file: /run/secrets/cookie-key\n  valkey:\n    address: redis://a\n",
+            &[
+                ("RELAY_SERVICE__COOKIE_KEY", "from-env"),
+                ("RELAY_SERVICE__VALKEY__ADDRESS_FILE", "/run/secrets/valkey"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            raw["service"],
+            yaml("{cookie_key: from-env, valkey: {address_file: /run/secrets/valkey}}")
+        );
+
+        let err = overridden(
+            "",
+            &[
+                ("RELAY_SERVICE__COOKIE_KEY", "a"),
+                ("RELAY_SERVICE__COOKIE_KEY_FILE", "/b"),
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "set either RELAY_SERVICE__COOKIE_KEY or RELAY_SERVICE__COOKIE_KEY_FILE, not both"
+        );
+    }
+
+    #[test]
+    fn env_must_name_a_setting() {
+        for name in [
+            "RELAY_SERVICE__BIND_PROT",
+            "RELAY_SERVICE__BIND_PORT__X",
+            "RELAY_SERVICE__BIND_PORT_FILE",
+            "RELAY_API__APPS____CLIENT_SECRET",
+            "RELAY_NOTHING__HERE",
+        ] {
+            let err = overridden("", &[(name, "1")]).unwrap_err();
+            assert_eq!(err.to_string(), format!("{} does not name a setting", name));
+        }
+
+        // Variables without a separator aren't settings, e.g. RELAY_CONFIG.
+        let raw = overridden("a: 1\n", &[("RELAY_CONFIG", "x"), ("OTHER__X", "y")]).unwrap();
+        assert_eq!(raw, yaml("a: 1\n"));
+    }
+
+    #[test]
+    fn settings_cover_the_config() {
+        let config = yaml(
+            "
+api:
+  apps:
+    - {name: a, client_id: 1, client_secret: s, redirect_url: http://r}
+  scope: [identify]
+  allowed_scopes: [public]
+  base_url: http://b
+  auth_url: http://a
+  api_url: http://p
+service:
+  bind_host: 0.0.0.0
+  bind_port: 1
+  max_concurrent_requests: 1
+  cookie_key: k
+  valkey: {address: redis://v}
+  rate_limit:
+    max_queued_requests: 1
+    queue_timeout_ms: 1
+    per_ip: {burst: 1, per_second: 1.0}
+    per_session: {burst: 1, per_second: 1.0}
+  trusted_proxies: [10.0.0.0/8]
+  forwarded_header: forwarded
+  security_headers: {html: {x-a: b}, api: {x-c: d}}
+  theme_dir: /t
+admin: {user_ids: [1]}
+access:
+  allowed_user_ids: [1]
+  allowed_groups: [gmt]
+  blocked_user_ids: [2]
+  max_sessions_per_user: 1
+  max_users: 1
+  registration_open: false
+cookie:
+  secure: false
+  same_site: strict
+  domain: d
+  path: /p
+  max_age_secs: 1
+  session_lifetime_secs: 1
+",
+        );
+        let config =
+            serde_yaml::to_value(serde_yaml::from_value::<Config>(config).unwrap()).unwrap();
+
+        fn check(node: &Value, path: &mut Vec<String>) {
+            let children = match node {
+                Value::Mapping(map) => map
+                    .iter()
+                    .map(|(k, v)| (k.as_str().unwrap().to_owned(), v))
+                    .collect::<Vec<_>>(),
+                Value::Sequence(items) => items
+                    .iter()
+                    .enumerate()
+                    .map(|(i, v)| (i.to_string(), v))
+                    .collect(),
+                _ => Vec::new(),
+            };
+            if path.len() > 1 {
+                let segments = path.iter().map(String::as_str).collect::<Vec<_>>();
+                let expected = match node {
+                    Value::String(_) => Kind::Text,
+                    _ => Kind::Yaml,
+                };
+                assert_eq!(
+                    setting_kind(&segments),
+                    Some(expected),
+                    "{}",
+                    path.join(".")
+                );
+            }
+            for (key, child) in children {
+                path.push(key);
+                check(child, path);
+                path.pop();
+            }
+        }
+        check(&config, &mut Vec::new());
+    }
+
+    #[test]
+    fn secret_files_are_read() {
+        let secret = secret_file("secret_files_are_read", "hunter2\r\n\n");
+        let mut raw = yaml(&format!(
+            "api:\n  apps:\n    - name: a\n      client_secret_file: {}\nservice:\n  cookie_key: plain\n",
+            secret
+        ));
+        read_secret_files(&mut raw, "").unwrap();
+        std::fs::remove_file(&secret).unwrap();
+        assert_eq!(
+            raw,
+            yaml("api:\n  apps:\n    - name: a\n      client_secret: hunter2\nservice:\n  cookie_key: plain\n")
+        );
+    }
+
+    #[test]
+    fn secret_files_errors() {
+        let err = |file: &str| {
+            read_secret_files(&mut yaml(file), "")
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            err("service:\n  cookie_key: a\n  cookie_key_file: /b\n"),
+            "set either service.cookie_key or service.cookie_key_file, not both"
+        );
+        assert_eq!(
+            err("api:\n  apps:\n    - client_secret_file: 1\n"),
+            "api.apps.0.client_secret_file must be a path"
+        );
+        assert!(
+            err("service:\n  cookie_key_file: /nonexistent/relay-secret\n").starts_with(
+                "failed to read service.cookie_key_file from /nonexistent/relay-secret: "
+            )
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+
+use chrono::Utc;
+use nanoid::nanoid;
+use sessions::Storage;
+
+use crate::api_keys;
+use crate::handlers::auth::SESSION_FIELD_DEVICE;
+use crate::model::{Device, DeviceInfo};
+use crate::storage::{ValkeyStorage, USER_SESSIONS_PREFIX};
+
+const DEVICE_ID_LENGTH: usize = 12;
+const MAX_USER_AGENT_LENGTH: usize = 200;
+
+fn user_sessions_key(user_id: u32) -> String {
+    format!("{}{}", USER_SESSIONS_PREFIX, user_id)
+}
+
+pub fn describe(user_agent: Option<&str>) -> DeviceInfo {
+    let now = Utc::now().timestamp();
+    DeviceInfo {
+        id: nanoid!(DEVICE_ID_LENGTH),
+        user_agent: user_agent.map(|ua| ua.chars().take(MAX_USER_AGENT_LENGTH).collect()),
+        ctime: now,
+        last_seen: now,
+    }
+}
+
+/// Remember that the session belongs to the user. Devices are referred to by their own ids, never by session ids.
+pub async fn register(
+    storage: &ValkeyStorage,
+    user_id: u32,
+    device: &DeviceInfo,
+    session_id: &str,
+) -> eyre::Result<()> {
+    storage
+        .hset_json(&user_sessions_key(user_id), &device.id, &session_id)
+        .await
+}
+
+pub async fn forget(storage: &ValkeyStorage, user_id: u32, device_id: &str) -> eyre::Result<()> {
+    storage.hdel(&user_sessions_key(user_id), device_id).await
+}
+
+/// List all live sessions of the user. Entries of sessions which have expired in the meantime are dropped.
+/// The device of the current session is passed along, since a session is only saved once its request is handled.
+pub async fn list(
+    storage: &ValkeyStorage,
+    user_id: u32,
+    current_session_id: &str,
+    current_device: Option<&DeviceInfo>,
+) -> eyre::Result<Vec<Device>> {
+    let mut devices = Vec::new();
+    for (device_id, session_id) in storage
+        .hgetall_json::<String>(&user_sessions_key(user_id))
+        .await?
+    {
+        let session = match storage.get(&session_id).await {
+            Ok(Some(session)) => session,
+            Ok(None) | Err(_) => {
+                forget(storage, user_id, &device_id).await?;
+                continue;
+            }
+        };
+        let info = match current_device.filter(|_| session_id == current_session_id) {
+            Some(info) => Some(info.clone()),
+            None => session
+                .get(SESSION_FIELD_DEVICE)
+                .and_then(|v| serde_json::from_value::<DeviceInfo>(v.clone()).ok()),
+        };
+        // The session is still being logged in.
+        let Some(info) = info else {
+            continue;
+        };
+
+        let keys = api_keys::list(storage, &session_id).await?;
+        devices.push(Device {
+            id: device_id,
+            current: session_id == current_session_id,
+            user_agent: info.user_agent,
+            clients: keys.iter().map(|k| k.label.clone()).collect(),
+            ctime: info.ctime,
+            last_used: keys
+                .iter()
+                .filter_map(|k| k.last_used)
+                .fold(info.last_seen, i64::max),
+        });
+    }
+
+    devices.sort_by_key(|d| d.ctime);
+    Ok(devices)
+}
+
+/// Drop the entries of sessions which have expired, and count the ones left.
+/// The record of a user goes away along with the last entry, since Valkey doesn't keep empty hashes.
+async fn prune(storage: &ValkeyStorage, user_id: u32) -> eyre::Result<usize> {
+    let mut live = 0;
+    for (device_id, session_id) in sessions(storage, user_id).await? {
+        if storage.exists(&session_id).await? {
+            live += 1;
+        } else {
+            forget(storage, user_id, &device_id).await?;
+        }
+    }
+    Ok(live)
+}
+
+/// Ids of all users who still have live sessions.
+pub async fn users(storage: &ValkeyStorage) -> eyre::Result<Vec<u32>> {
+    let mut users = Vec::new();
+    for user_id in storage
+        .keys_with_prefix(USER_SESSIONS_PREFIX)
+        .await?
+        .iter()
+        .filter_map(|id| id.parse().ok())
+    {
+        if prune(storage, user_id).await? > 0 {
+            users.push(user_id);
+        }
+    }
+    Ok(users)
+}
+
+pub async fn is_known_user(storage: &ValkeyStorage, user_id: u32) -> eyre::Result<bool> {
+    Ok(prune(storage, user_id).await? > 0)
+}
+
+/// Session ids of the user's devices, keyed by device id. Unlike `list`, this doesn't check whether the sessions are still alive.
+pub async fn sessions(
+    storage: &ValkeyStorage,
+    user_id: u32,
+) -> eyre::Result<HashMap<String, String>> {
+    storage
+        .hgetall_json::<String>(&user_sessions_key(user_id))
+        .await
+}
+
+/// Log the device out remotely, along with all API keys issued to it. Returns `false` if there was no such device.
+pub async fn revoke(storage: &ValkeyStorage, user_id: u32, device_id: &str) -> eyre::Result<bool> {
+    let key = user_sessions_key(user_id);
+    match storage.hget_json::<String>(&key, device_id).await? {
+        Some(session_id) => {
+            api_keys::revoke_all(storage, &session_id).await?;
+            storage.remove(&session_id).await?;
+            forget(storage, user_id, device_id).await?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
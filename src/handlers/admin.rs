@@ -0,0 +1,141 @@
+use viz::{IntoResponse, Request, RequestExt, Response, ResponseExt, StatusCode};
+
+use super::auth::SESSION_FIELD_USER_ID;
+use crate::admin;
+use crate::config::Config;
+use crate::middleware;
+use crate::refresher::{self, RefresherStatus};
+use crate::secrets;
+use crate::storage::{self, ValkeyStorage};
+use crate::templates::admin::{AdminDashboardPage, AdminSessionPage};
+
+const ADMIN_PATH: &str = "/admin";
+const ADMIN_SESSIONS_PATH: &str = "/admin/sessions";
+
+/// Let through only operators listed in the config, sending everyone else who isn't logged in to the login page.
+fn require_admin(r: &Request) -> viz::Result<(Config, ValkeyStorage)> {
+    let config = r
+        .state::<Config>()
+        .ok_or_else(|| StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+    let storage = r
+        .state::<ValkeyStorage>()
+        .ok_or_else(|| StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+
+    match r.session().get::<u32>(SESSION_FIELD_USER_ID)? {
+        None => Err(Response::redirect_with_status("/auth", StatusCode::FOUND).into_error()),
+        Some(user_id) if config.admin.is_admin(user_id) => Ok((config, storage)),
+        Some(user_id) => {
+            log::warn!("User #{} tried to access the admin dashboard", user_id);
+            Err(StatusCode::FORBIDDEN.into_error())
+        }
+    }
+}
+
+/// The session named in the path by its fingerprint or its id, refusing anything that couldn't be either before it reaches Valkey.
+async fn session_id_param(r: &Request, storage: &ValkeyStorage) -> viz::Result<String> {
+    let id = r.param::<String>("id")?;
+    if crate::verify_session_id(&id) && storage::is_session_key(&id) {
+        return Ok(id);
+    }
+    if !secrets::is_fingerprint(&id) {
+        return Err(StatusCode::NOT_FOUND.into_error());
+    }
+    match admin::find_session(storage, &id).await {
+        Err(e) => {
+            log::error!("Error while looking up a session in Valkey: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR.into_error())
+        }
+        Ok(None) => Err(StatusCode::NOT_FOUND.into_error()),
+        Ok(Some(session_id)) => Ok(session_id),
+    }
+}
+
+async fn show_session(
+    r: &Request,
+    storage: &ValkeyStorage,
+    session_id: &str,
+    notice: Option<&str>,
+) -> viz::Result<Response> {
+    match admin::inspect(storage, session_id).await {
+        Err(e) => {
+            log::error!("Error while loading a session from Valkey: {}", e);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+        Ok(None) => Ok(StatusCode::NOT_FOUND.into_response()),
+        Ok(Some((session, metadata))) => {
+            let session_url = format!("{}/{}", ADMIN_SESSIONS_PATH, session.fingerprint);
+            Ok(Response::html(
+                AdminSessionPage {
+                    session,
+                    metadata: serde_json::to_string_pretty(&metadata).unwrap_or_default(),
+                    notice,
+                    dashboard_url: ADMIN_PATH,
+                    refresh_url: &format!("{}/refresh", session_url),
+                    revoke_url: &format!("{}/revoke", session_url),
+                    csrf_token: &middleware::csrf_token(r),
+                }
+                .to_string(),
+            ))
+        }
+    }
+}
+
+pub async fn dashboard(r: Request) -> viz::Result<Response> {
+    let (_, storage) = require_admin(&r)?;
+    let status = r
+        .state::<RefresherStatus>()
+        .ok_or_else(|| StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+
+    match admin::overview(&storage).await {
+        Err(e) => {
+            log::error!(
+                "Error while collecting session statistics from Valkey: {}",
+                e
+            );
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+        Ok(overview) => Ok(Response::html(
+            AdminDashboardPage {
+                overview,
+                last_sweep: status.last_sweep().map(|dt| dt.timestamp()),
+                refresher_alive: status.is_alive(),
+                sessions_url: ADMIN_SESSIONS_PATH,
+            }
+            .to_string(),
+        )),
+    }
+}
+
+pub async fn session(r: Request) -> viz::Result<Response> {
+    let (_, storage) = require_admin(&r)?;
+    let session_id = session_id_param(&r, &storage).await?;
+    show_session(&r, &storage, &session_id, None).await
+}
+
+pub async fn refresh_session(r: Request) -> viz::Result<Response> {
+    let (config, storage) = require_admin(&r)?;
+    let session_id = session_id_param(&r, &storage).await?;
+
+    let notice = match refresher::refresh_session(&config, &storage, &session_id).await {
+        Ok(()) => "The token has been refreshed.".to_owned(),
+        Err(e) => format!("Failed to refresh the token: {}", e),
+    };
+    show_session(&r, &storage, &session_id, Some(&notice)).await
+}
+
+pub async fn revoke_session(r: Request) -> viz::Result<Response> {
+    let (_, storage) = require_admin(&r)?;
+    let session_id = session_id_param(&r, &storage).await?;
+
+    match admin::revoke(&storage, &session_id).await {
+        Err(e) => {
+            log::error!("Error while revoking a session in Valkey: {}", e);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+        Ok(true) => Ok(Response::redirect_with_status(
+            ADMIN_PATH,
+            StatusCode::SEE_OTHER,
+        )),
+        Ok(false) => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
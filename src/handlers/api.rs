@@ -1,32 +1,113 @@
+use serde::de::DeserializeOwned;
 use sessions::Storage;
 use viz::{IntoResponse, Request, RequestExt, Response, ResponseExt, StatusCode};
 
-use super::auth::SESSION_FIELD_TOKEN;
+use super::auth::{
+    SESSION_FIELD_APP, SESSION_FIELD_SCOPES, SESSION_FIELD_TOKEN, SESSION_FIELD_USER,
+    SESSION_FIELD_USER_ID,
+};
+use crate::api_keys;
+use crate::app_tokens;
+use crate::config::Config;
+use crate::devices;
+use crate::handoff;
+use crate::middleware;
+use crate::model::{
+    ApiKeyForm, ApiKeyInfo, ApiKeyOwner, AppQuery, ExchangeRequest, PairingChallenge,
+    PairingPollRequest, PairingStartRequest, ScopeQuery, UserCompact,
+};
+use crate::pairing;
+use crate::scopes;
+use crate::secrets::redact;
 use crate::storage::ValkeyStorage;
 
-const SESSION_HEADER_NAME: &str = "X-Relay-Session";
+pub const API_KEY_HEADER_NAME: &str = "X-Relay-Key";
 
-pub async fn token(r: Request) -> viz::Result<Response> {
-    let session_header = r
-        .headers()
-        .get(SESSION_HEADER_NAME)
+async fn authenticate(r: &Request, storage: &ValkeyStorage) -> viz::Result<ApiKeyOwner> {
+    let key = r
+        .header::<_, String>(API_KEY_HEADER_NAME)
         .ok_or(StatusCode::UNAUTHORIZED.into_error())?;
-    let session_id = session_header.to_str().unwrap();
 
+    match api_keys::authenticate(storage, &key).await {
+        Err(e) => {
+            log::error!("Error while looking up an API key in Valkey: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR.into_error())
+        }
+        Ok(None) => Err(StatusCode::UNAUTHORIZED.into_error()),
+        Ok(Some(owner)) => {
+            if let Ok(user_id) = session_user_id(storage, &owner.session_id).await {
+                middleware::record_user(r, user_id);
+            }
+            Ok(owner)
+        }
+    }
+}
+
+async fn session_field<T: DeserializeOwned>(
+    storage: &ValkeyStorage,
+    session_id: &str,
+    field: &str,
+) -> viz::Result<Option<T>> {
+    match storage.get(session_id).await {
+        Err(e) => {
+            log::error!(
+                "Error while loading the session of {} from Valkey: {}",
+                redact(session_id),
+                e
+            );
+            Err(StatusCode::INTERNAL_SERVER_ERROR.into_error())
+        }
+        Ok(session) => Ok(session
+            .and_then(|s| s.get(field).cloned())
+            .and_then(|v| serde_json::from_value(v).ok())),
+    }
+}
+
+async fn session_user_id(storage: &ValkeyStorage, session_id: &str) -> viz::Result<u32> {
+    session_field::<u32>(storage, session_id, SESSION_FIELD_USER_ID)
+        .await?
+        .ok_or(StatusCode::NOT_FOUND.into_error())
+}
+
+pub async fn token(r: Request) -> viz::Result<Response> {
+    let config = r
+        .state::<Config>()
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
     let session_storage: ValkeyStorage = r
         .state()
         .ok_or(StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+    let owner = authenticate(&r, &session_storage).await?;
+    let demanded = r
+        .query::<ScopeQuery>()
+        .map(|q| scopes::parse(&q.scope))
+        .unwrap_or_default();
 
-    match session_storage.get(session_id).await {
+    match session_storage.get(&owner.session_id).await {
         Err(e) => {
             log::error!(
                 "Error while loading the token of {} from Valkey: {}",
-                session_id,
+                redact(&owner.session_id),
                 e
             );
             Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
         }
         Ok(Some(session)) => {
+            let granted = session
+                .get(SESSION_FIELD_SCOPES)
+                .and_then(|v| serde_json::from_value::<Vec<String>>(v.clone()).ok())
+                .unwrap_or_else(|| scopes::default_scopes(&config));
+            let missing = scopes::missing(&granted, &demanded);
+            if !missing.is_empty() {
+                return Ok((
+                    StatusCode::FORBIDDEN,
+                    format!(
+                        "the session lacks the following scopes: {}",
+                        missing.join(", ")
+                    ),
+                )
+                    .into_response());
+            }
+
             if let Some(t) = session.get(SESSION_FIELD_TOKEN) {
                 Ok(Response::json(t.to_string()).unwrap())
             } else {
@@ -36,3 +117,233 @@ pub async fn token(r: Request) -> viz::Result<Response> {
         Ok(None) => Ok(StatusCode::NOT_FOUND.into_response()),
     }
 }
+
+pub async fn me(r: Request) -> viz::Result<Response> {
+    let session_storage: ValkeyStorage = r
+        .state()
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+    let owner = authenticate(&r, &session_storage).await?;
+
+    match session_field::<UserCompact>(&session_storage, &owner.session_id, SESSION_FIELD_USER)
+        .await?
+    {
+        Some(user) => Ok(Response::json(user).unwrap()),
+        None => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
+
+pub async fn app_token(r: Request) -> viz::Result<Response> {
+    let config = r
+        .state::<Config>()
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+    let session_storage: ValkeyStorage = r
+        .state()
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+    let owner = authenticate(&r, &session_storage).await?;
+
+    // Unless asked otherwise, serve the token of the app which issued the caller's session.
+    let app_name = match r.query::<AppQuery>() {
+        Ok(query) => Some(query.app),
+        Err(_) => {
+            session_field::<String>(&session_storage, &owner.session_id, SESSION_FIELD_APP).await?
+        }
+    };
+    let app = config
+        .api
+        .app(app_name.as_deref())
+        .ok_or(StatusCode::NOT_FOUND.into_error())?;
+
+    match app_tokens::get(&config, &session_storage, app).await {
+        Err(e) => {
+            log::error!("Error while obtaining the app token of {}: {}", app.name, e);
+            Ok(StatusCode::BAD_GATEWAY.into_response())
+        }
+        Ok(token) => Ok(Response::json(token).unwrap()),
+    }
+}
+
+pub async fn list_keys(r: Request) -> viz::Result<Response> {
+    let session_storage: ValkeyStorage = r
+        .state()
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+    let owner = authenticate(&r, &session_storage).await?;
+
+    match api_keys::list(&session_storage, &owner.session_id).await {
+        Err(e) => {
+            log::error!("Error while loading API keys from Valkey: {}", e);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+        Ok(keys) => {
+            Ok(Response::json(keys.into_iter().map(ApiKeyInfo::from).collect::<Vec<_>>()).unwrap())
+        }
+    }
+}
+
+pub async fn create_key(mut r: Request) -> viz::Result<Response> {
+    let request = r.json::<ApiKeyForm>().await.unwrap_or_default();
+    let session_storage: ValkeyStorage = r
+        .state()
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+    let owner = authenticate(&r, &session_storage).await?;
+
+    match api_keys::create(
+        &session_storage,
+        &owner.session_id,
+        request.label.as_deref(),
+    )
+    .await
+    {
+        Err(e) => {
+            log::error!("Error while saving a new API key to Valkey: {}", e);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+        Ok(key) => Ok(Response::json(key).unwrap()),
+    }
+}
+
+pub async fn revoke_key(r: Request) -> viz::Result<Response> {
+    let key_id = r.param::<String>("id")?;
+    let session_storage: ValkeyStorage = r
+        .state()
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+    let owner = authenticate(&r, &session_storage).await?;
+
+    match api_keys::revoke(&session_storage, &owner.session_id, &key_id).await {
+        Err(e) => {
+            log::error!("Error while revoking an API key in Valkey: {}", e);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+        Ok(true) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Ok(false) => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
+
+pub async fn list_devices(r: Request) -> viz::Result<Response> {
+    let session_storage: ValkeyStorage = r
+        .state()
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+    let owner = authenticate(&r, &session_storage).await?;
+    let user_id = session_user_id(&session_storage, &owner.session_id).await?;
+
+    match devices::list(&session_storage, user_id, &owner.session_id, None).await {
+        Err(e) => {
+            log::error!("Error while loading devices from Valkey: {}", e);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+        Ok(devices) => Ok(Response::json(devices).unwrap()),
+    }
+}
+
+pub async fn revoke_device(r: Request) -> viz::Result<Response> {
+    let device_id = r.param::<String>("id")?;
+    let session_storage: ValkeyStorage = r
+        .state()
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+    let owner = authenticate(&r, &session_storage).await?;
+    let user_id = session_user_id(&session_storage, &owner.session_id).await?;
+
+    match devices::revoke(&session_storage, user_id, &device_id).await {
+        Err(e) => {
+            log::error!("Error while revoking a device in Valkey: {}", e);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+        Ok(true) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Ok(false) => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
+
+pub async fn start_pairing(mut r: Request) -> viz::Result<Response> {
+    let request = r.json::<PairingStartRequest>().await.unwrap_or_default();
+    let config = r
+        .state::<Config>()
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+    let session_storage: ValkeyStorage = r
+        .state()
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+
+    let app = config
+        .api
+        .app(request.app.as_deref())
+        .ok_or(StatusCode::NOT_FOUND.into_error())?;
+    let verification_url = pairing::verification_url(app).map_err(|e| {
+        log::error!("Failed to build the pairing page URL: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR.into_error()
+    })?;
+
+    let scope = match request.scope {
+        Some(requested) => match scopes::validate(&config, requested) {
+            Ok(scope) => Some(scope),
+            Err(e) => return Ok((StatusCode::BAD_REQUEST, e).into_response()),
+        },
+        None => None,
+    };
+
+    match pairing::start(&session_storage, scope, request.app.clone()).await {
+        Err(e) => {
+            log::error!("Error while saving a new pairing to Valkey: {}", e);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+        Ok((device_code, p)) => {
+            let user_code = pairing::format_user_code(&p.user_code);
+            let mut verification_url_complete = verification_url.clone();
+            verification_url_complete
+                .query_pairs_mut()
+                .append_pair("user_code", &user_code);
+
+            Ok(Response::json(PairingChallenge {
+                device_code,
+                user_code,
+                verification_url: verification_url.to_string(),
+                verification_url_complete: verification_url_complete.to_string(),
+                expires_in: pairing::PAIRING_LIFETIME_SECS,
+                interval: pairing::PAIRING_POLL_INTERVAL_SECS,
+            })
+            .unwrap())
+        }
+    }
+}
+
+pub async fn poll_pairing(mut r: Request) -> viz::Result<Response> {
+    let request = r.json::<PairingPollRequest>().await?;
+    let session_storage: ValkeyStorage = r
+        .state()
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+
+    match pairing::poll(
+        &session_storage,
+        &request.device_code,
+        request.label.as_deref(),
+    )
+    .await
+    {
+        Err(e) => {
+            log::error!("Error while loading a pairing from Valkey: {}", e);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+        Ok(Some(status)) => Ok(Response::json(status).unwrap()),
+        Ok(None) => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
+
+pub async fn exchange(mut r: Request) -> viz::Result<Response> {
+    let request = r.json::<ExchangeRequest>().await?;
+    let session_storage: ValkeyStorage = r
+        .state()
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+
+    match handoff::exchange(
+        &session_storage,
+        &request.code,
+        &request.nonce,
+        request.label.as_deref(),
+    )
+    .await
+    {
+        Err(e) => {
+            log::error!("Error while loading an exchange code from Valkey: {}", e);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+        Ok(Some(key)) => Ok(Response::json(key).unwrap()),
+        Ok(None) => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
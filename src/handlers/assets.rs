@@ -0,0 +1,64 @@
+use std::path::Path;
+
+use viz::handlers::serve::Dir;
+use viz::header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use viz::{Handler, IntoResponse, Request, RequestExt, Response, StatusCode};
+
+use crate::config::Config;
+
+pub const ASSETS_PATH: &str = "/static";
+
+const CACHE_POLICY: &str = "public, max-age=86400";
+
+/// Assets built into the binary, so that pages don't depend on any CDN.
+const EMBEDDED_ASSETS: [(&str, &str, &[u8]); 2] = [
+    (
+        "relay.css",
+        "text/css; charset=utf-8",
+        include_bytes!("../../static/relay.css"),
+    ),
+    // Linked from every page after relay.css, and empty unless the theme provides one.
+    ("theme.css", "text/css; charset=utf-8", b""),
+];
+
+fn serve_embedded(r: &Request, name: &str) -> Response {
+    let (content_type, data) = match EMBEDDED_ASSETS.iter().find(|(n, _, _)| *n == name) {
+        Some((_, content_type, data)) => (*content_type, *data),
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    // Embedded assets only change with the binary.
+    let etag = format!("\"{}-{}\"", env!("CARGO_PKG_VERSION"), data.len());
+    if r.header::<_, String>(IF_NONE_MATCH).as_deref() == Some(etag.as_str()) {
+        return StatusCode::NOT_MODIFIED.into_response();
+    }
+
+    let mut response = data.into_response();
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, content_type.parse().unwrap());
+    headers.insert(ETAG, etag.parse().unwrap());
+    headers.insert(CACHE_CONTROL, CACHE_POLICY.parse().unwrap());
+    response
+}
+
+/// Serve an asset from the theme directory if it has one by that name, or the built-in one otherwise.
+pub async fn asset(r: Request) -> viz::Result<Response> {
+    let config = r
+        .state::<Config>()
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+    let name = r.param::<String>("name")?;
+    if name.contains(['/', '\\']) || name.starts_with('.') {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    if let Some(theme_dir) = &config.service.theme_dir {
+        if Path::new(theme_dir).join(&name).is_file() {
+            let mut response = Dir::new(theme_dir).call(r).await?;
+            response
+                .headers_mut()
+                .insert(CACHE_CONTROL, CACHE_POLICY.parse().unwrap());
+            return Ok(response);
+        }
+    }
+    Ok(serve_embedded(&r, &name))
+}
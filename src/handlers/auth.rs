@@ -1,55 +1,149 @@
 use std::collections::HashMap;
 
-use nanoid::nanoid;
+use chrono::Utc;
 use reqwest;
+use serde::de::DeserializeOwned;
+use viz::header::USER_AGENT;
 use viz::{IntoResponse, Request, RequestExt, Response, ResponseExt, StatusCode};
 
-use crate::config::{self, Config};
-use crate::model::{AccessToken, OAuth2FeedbackQuery, UserCompact};
-use crate::storage::SESSION_COOKIE_NAME;
-use crate::templates::auth::{AuthErrorPage, AuthInitiationPage, AuthSuccessPage};
+use crate::access;
+use crate::api_keys;
+use crate::config::{self, App, Config};
+use crate::devices;
+use crate::handoff::{self, HANDOFF_PAGE_PATH};
+use crate::login;
+use crate::metrics;
+use crate::middleware;
+use crate::model::{
+    AccessToken, ApiKeyForm, ApiKeyRevocationForm, AppQuery, DeviceInfo, DeviceRevocationForm,
+    HandoffQuery, LoginAttempt, NewApiKey, OAuth2Error, OAuth2FeedbackQuery, PairingForm,
+    ScopeQuery, UserCompact,
+};
+use crate::pairing::{self, PAIRING_PAGE_PATH};
+use crate::refresher;
+use crate::scopes;
+use crate::storage::{ValkeyStorage, SESSION_COOKIE_NAME};
+use crate::templates::auth::{
+    AuthErrorPage, AuthHandoffPage, AuthInitiationPage, AuthPairingPage, AuthSuccessPage,
+};
 
-pub const API_AUTHORIZATION_URL: &str = "https://osu.ppy.sh/oauth/authorize";
-pub const API_AUTHENTICATION_URL: &str = "https://osu.ppy.sh/oauth/token";
-
-pub const SESSION_FIELD_STATE: &str = "state";
+pub const SESSION_FIELD_LOGIN_ATTEMPTS: &str = "login_attempts";
 pub const SESSION_FIELD_TOKEN: &str = "token";
+pub const SESSION_FIELD_PAIRING: &str = "pairing";
+pub const SESSION_FIELD_HANDOFF: &str = "handoff";
+pub const SESSION_FIELD_USER_ID: &str = "user_id";
+pub const SESSION_FIELD_USER: &str = "user";
+pub const SESSION_FIELD_DEVICE: &str = "device";
+pub const SESSION_FIELD_SCOPES: &str = "scopes";
+pub const SESSION_FIELD_REQUESTED_SCOPES: &str = "requested_scopes";
+pub const SESSION_FIELD_APP: &str = "app";
+pub const SESSION_FIELD_REQUESTED_APP: &str = "requested_app";
 
-fn make_authorization_url(config: &Config) -> (reqwest::Url, String) {
-    let state = nanoid!(10);
-    let url = reqwest::Url::parse_with_params(
-        API_AUTHORIZATION_URL,
+fn make_authorization_url(api: &config::API, app: &App, attempt: &LoginAttempt) -> reqwest::Url {
+    reqwest::Url::parse_with_params(
+        &api.authorization_url(),
         &[
-            ("client_id", config.api.client_id.to_string()),
-            ("redirect_uri", config.api.redirect_url.clone()),
+            ("client_id", app.client_id.to_string()),
+            ("redirect_uri", app.redirect_url.clone()),
             ("response_type", "code".to_owned()),
-            ("scope", config.api.scope.join(" ")),
-            ("state", state.clone()),
+            ("scope", attempt.scope.join(" ")),
+            ("state", attempt.state.clone()),
+            ("code_challenge", login::code_challenge(attempt)),
+            ("code_challenge_method", "S256".to_owned()),
         ],
     )
-    .unwrap();
-    (url, state)
+    .unwrap()
 }
 
-fn make_authentication_request(config: &Config, query: &OAuth2FeedbackQuery) -> reqwest::Request {
+fn make_authentication_request(
+    api: &config::API,
+    app: &App,
+    query: &OAuth2FeedbackQuery,
+    attempt: &LoginAttempt,
+) -> reqwest::Request {
     reqwest::Client::new()
-        .post(API_AUTHENTICATION_URL)
+        .post(api.token_url())
         .form(&HashMap::from([
-            ("client_id", config.api.client_id.to_string()),
-            ("client_secret", config.api.client_secret.clone()),
+            ("client_id", app.client_id.to_string()),
+            ("client_secret", app.client_secret.expose().to_owned()),
             ("code", query.code.clone()),
             ("grant_type", "authorization_code".to_owned()),
-            ("redirect_uri", config.api.redirect_url.clone()),
-            ("state", query.state.clone()),
+            ("redirect_uri", app.redirect_url.clone()),
+            ("code_verifier", attempt.code_verifier.clone()),
         ]))
         .header("Accept", "application/json")
         .build()
         .unwrap()
 }
 
+/// Scopes of the session's token. Sessions from before scopes were tracked have the default ones.
+fn granted_scopes(r: &Request, config: &Config) -> Vec<String> {
+    r.session()
+        .get::<Vec<String>>(SESSION_FIELD_SCOPES)
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| scopes::default_scopes(config))
+}
+
+/// The app which issued the session's token. Sessions from before apps were tracked belong to the default one.
+fn current_app<'a>(r: &Request, config: &'a Config) -> &'a App {
+    r.session()
+        .get::<String>(SESSION_FIELD_APP)
+        .ok()
+        .flatten()
+        .and_then(|name| config.api.app(Some(&name)))
+        .unwrap_or_else(|| config.api.default_app())
+}
+
+fn requested_app<'a>(r: &Request, config: &'a Config) -> &'a App {
+    r.session()
+        .get::<String>(SESSION_FIELD_REQUESTED_APP)
+        .ok()
+        .flatten()
+        .and_then(|name| config.api.app(Some(&name)))
+        .unwrap_or_else(|| current_app(r, config))
+}
+
+/// Browsers drop secure cookies set over plain HTTP, so a login started there could never finish.
+fn upgrade_to_https(r: &Request, config: &Config) -> Option<Response> {
+    let client = middleware::client_info(r)?;
+    // Proxies which terminate TLS without saying so must not be sent around in circles.
+    if client.scheme.as_deref() != Some("http") || !config.cookie.secure {
+        return None;
+    }
+    let mut url = reqwest::Url::parse(&current_app(r, config).redirect_url).ok()?;
+    if url.scheme() != "https" {
+        return None;
+    }
+    url.set_path(r.path());
+    url.set_query(r.uri().query());
+    Some(Response::redirect_with_status(
+        url.as_str(),
+        StatusCode::PERMANENT_REDIRECT,
+    ))
+}
+
 fn show_authentication_page(r: Request, config: &config::Config) -> viz::Result<Response> {
-    let (url, state) = make_authorization_url(config);
-    match r.session().set(SESSION_FIELD_STATE, state) {
+    let requested = r
+        .session()
+        .get::<Vec<String>>(SESSION_FIELD_REQUESTED_SCOPES)?
+        .unwrap_or_else(|| scopes::default_scopes(config));
+    // Logging in again to get more scopes shouldn't take away the ones other clients rely on.
+    let scope = match r.session().get::<AccessToken>(SESSION_FIELD_TOKEN)? {
+        Some(_) => scopes::union(&requested, &granted_scopes(&r, config)),
+        None => requested,
+    };
+
+    let app = requested_app(&r, config);
+    let attempt = login::start(app, scope.clone());
+    let url = make_authorization_url(&config.api, app, &attempt);
+    r.session().set(SESSION_FIELD_REQUESTED_APP, &app.name)?;
+    r.session().set(SESSION_FIELD_REQUESTED_SCOPES, scope)?;
+
+    // Every tab gets its own attempt, so that starting a login in one doesn't break the other.
+    let mut attempts = pending_login_attempts(&r);
+    login::remember(&mut attempts, attempt);
+    match r.session().set(SESSION_FIELD_LOGIN_ATTEMPTS, attempts) {
         Ok(_) => Ok(Response::html(
             AuthInitiationPage {
                 auth_url: url.as_ref(),
@@ -63,55 +157,242 @@ fn show_authentication_page(r: Request, config: &config::Config) -> viz::Result<
     }
 }
 
-fn show_success_page(
-    data: UserCompact,
-    token: AccessToken,
-    session_id: &str,
-) -> viz::Result<Response> {
-    Ok(Response::html(
-        AuthSuccessPage {
-            data,
-            token,
-            session_id,
-            logout_url: "/auth/logout",
+fn pending_login_attempts(r: &Request) -> Vec<LoginAttempt> {
+    r.session()
+        .get::<Vec<LoginAttempt>>(SESSION_FIELD_LOGIN_ATTEMPTS)
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+}
+
+/// Why a login couldn't be completed, in terms the user can act upon.
+#[derive(Debug)]
+enum AuthFailure {
+    AccessDenied,
+    Rejected(String),
+    Unreachable(String),
+    UnexpectedResponse(String),
+    CorruptCookie,
+    Forbidden(access::Denial),
+}
+
+impl AuthFailure {
+    fn heading(&self) -> &'static str {
+        match self {
+            AuthFailure::AccessDenied => "Access denied",
+            AuthFailure::Rejected(_) => "osu! rejected the login",
+            AuthFailure::Unreachable(_) => "osu! is unreachable",
+            AuthFailure::UnexpectedResponse(_) => "Unexpected response from osu!",
+            AuthFailure::CorruptCookie => "Invalid session",
+            AuthFailure::Forbidden(_) => "Not allowed",
         }
-        .to_string(),
-    ))
+    }
+
+    fn message(&self) -> String {
+        match self {
+            AuthFailure::AccessDenied => {
+                "you have declined to give relay access to your osu! account".to_owned()
+            }
+            AuthFailure::Rejected(description) => description.clone(),
+            AuthFailure::Unreachable(e) => format!("failed to reach osu! web: {}", e),
+            AuthFailure::UnexpectedResponse(e) => {
+                format!("failed to understand the response of osu! web: {}", e)
+            }
+            AuthFailure::CorruptCookie => {
+                "your session cookie is damaged or was issued by another relay -- log in again"
+                    .to_owned()
+            }
+            AuthFailure::Forbidden(denial) => denial.message(),
+        }
+    }
 }
 
-fn show_authentication_error(error: &str) -> viz::Result<Response> {
+fn show_error_page(r: &Request, heading: &str, error: &str) -> viz::Result<Response> {
     Ok(Response::html(
         AuthErrorPage {
+            heading,
             error,
+            request_id: &middleware::request_id(r),
             logout_url: "/auth/logout",
+            csrf_token: &middleware::csrf_token(r),
         }
         .to_string(),
     ))
 }
 
-async fn show_index_with_user_data(
-    client: reqwest::Client,
-    token: AccessToken,
-    session_id: &str,
-) -> viz::Result<Response> {
-    let user_data_request = client
-        .get(reqwest::Url::parse("https://osu.ppy.sh/api/v2/me").unwrap())
+fn show_authentication_error(r: &Request, error: &str) -> viz::Result<Response> {
+    show_error_page(r, "Authentication error", error)
+}
+
+fn show_authentication_failure(r: &Request, failure: AuthFailure) -> viz::Result<Response> {
+    log::warn!("Failed to authenticate a user: {:?}", failure);
+    show_error_page(r, failure.heading(), &failure.message())
+}
+
+/// Decode a response of osu! web, telling its own complaints apart from outages.
+async fn read_osu_response<T: DeserializeOwned>(
+    result: reqwest::Result<reqwest::Response>,
+) -> Result<T, AuthFailure> {
+    let response = result.map_err(|e| AuthFailure::Unreachable(e.to_string()))?;
+    let status = response.status();
+    let text = response
+        .text()
+        .await
+        .map_err(|e| AuthFailure::Unreachable(e.to_string()))?;
+
+    if status.is_client_error() {
+        return Err(AuthFailure::Rejected(
+            serde_json::from_str::<OAuth2Error>(&text)
+                .map(|e| e.describe())
+                .unwrap_or_else(|_| format!("osu! web responded with {}", status)),
+        ));
+    }
+    if !status.is_success() {
+        return Err(AuthFailure::UnexpectedResponse(format!(
+            "osu! web responded with {}",
+            status
+        )));
+    }
+    serde_json::from_str(&text).map_err(|e| AuthFailure::UnexpectedResponse(e.to_string()))
+}
+
+pub fn make_user_data_request(api: &config::API, token: &AccessToken) -> reqwest::Request {
+    reqwest::Client::new()
+        .get(api.api_endpoint("me"))
         .header("Accept", "application/json")
         .bearer_auth(token.access_token.to_owned())
         .build()
-        .unwrap();
+        .unwrap()
+}
 
-    match client.execute(user_data_request).await {
-        Err(e) => show_authentication_error(&format!(
-            "failed to check who you are through the osu! API: {}",
-            e
-        )),
-        Ok(response) => {
-            let text = response.text().await.unwrap();
-            let user_data: UserCompact = serde_json::from_str(&text).unwrap();
-            show_success_page(user_data, token, session_id)
+async fn fetch_user_data(
+    api: &config::API,
+    client: &reqwest::Client,
+    token: &AccessToken,
+) -> Result<UserCompact, AuthFailure> {
+    let result = client.execute(make_user_data_request(api, token)).await;
+    metrics::observe_osu_api_response("me", &result);
+    read_osu_response(result).await
+}
+
+/// The profile cached in the session at login, or a fresh one for sessions which don't have it yet.
+async fn session_user(
+    r: &Request,
+    config: &Config,
+    token: &AccessToken,
+) -> Result<UserCompact, AuthFailure> {
+    if let Ok(Some(user)) = r.session().get::<UserCompact>(SESSION_FIELD_USER) {
+        return Ok(user);
+    }
+    let user = fetch_user_data(&config.api, &reqwest::Client::new(), token).await?;
+    if let Err(e) = r.session().set(SESSION_FIELD_USER, &user) {
+        log::warn!("Failed to cache the user profile in the session: {}", e);
+    }
+    Ok(user)
+}
+
+/// Forget the login attempt which issued `state`, if any.
+fn discard_login_attempt(r: &Request, state: &str) -> viz::Result<Option<LoginAttempt>> {
+    let mut attempts = pending_login_attempts(r);
+    let attempt = login::take(&mut attempts, state);
+    r.session().set(SESSION_FIELD_LOGIN_ATTEMPTS, attempts)?;
+    Ok(attempt)
+}
+
+/// Add the browser session to the user's devices on the first visit, and mark it as seen on the following ones.
+async fn track_device(r: &Request, user_id: u32, session_id: &str) -> Result<(), String> {
+    let mut device = match r.session().get::<DeviceInfo>(SESSION_FIELD_DEVICE) {
+        Ok(Some(device)) => device,
+        _ => {
+            let storage = r
+                .state::<ValkeyStorage>()
+                .ok_or("session storage is unavailable")?;
+            let device = devices::describe(r.header::<_, String>(USER_AGENT).as_deref());
+            devices::register(&storage, user_id, &device, session_id)
+                .await
+                .map_err(|e| format!("failed to register your device: {}", e))?;
+            r.session()
+                .set(SESSION_FIELD_USER_ID, user_id)
+                .map_err(|e| e.to_string())?;
+            device
         }
+    };
+
+    device.last_seen = Utc::now().timestamp();
+    r.session()
+        .set(SESSION_FIELD_DEVICE, device)
+        .map_err(|e| e.to_string())
+}
+
+async fn show_index(
+    r: &Request,
+    user: UserCompact,
+    token: AccessToken,
+    session_id: &str,
+    new_key: Option<&NewApiKey>,
+    notice: Option<&str>,
+) -> viz::Result<Response> {
+    let config = r
+        .state::<config::Config>()
+        .ok_or_else(|| StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+    let storage = r
+        .state::<ValkeyStorage>()
+        .ok_or_else(|| StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+    if let Err(e) = storage.touch_session(session_id).await {
+        log::error!("Error while extending the lifetime of a session: {}", e);
     }
+    let keys = match api_keys::list(&storage, session_id).await {
+        Err(e) => {
+            return show_authentication_error(r, &format!("failed to load your API keys: {}", e))
+        }
+        Ok(keys) => keys,
+    };
+    let current_device = r
+        .session()
+        .get::<DeviceInfo>(SESSION_FIELD_DEVICE)
+        .ok()
+        .flatten();
+    let devices =
+        match devices::list(&storage, user.user_id, session_id, current_device.as_ref()).await {
+            Err(e) => {
+                return show_authentication_error(r, &format!("failed to load your devices: {}", e))
+            }
+            Ok(devices) => devices,
+        };
+    Ok(Response::html(
+        AuthSuccessPage {
+            profile_url: &config.api.user_profile_url(user.user_id),
+            data: user,
+            token,
+            keys,
+            devices,
+            new_key,
+            notice,
+            keys_url: "/auth/keys",
+            revoke_key_url: "/auth/keys/revoke",
+            revoke_device_url: "/auth/devices/revoke",
+            logout_url: "/auth/logout",
+            csrf_token: &middleware::csrf_token(r),
+        }
+        .to_string(),
+    ))
+}
+
+pub fn current_session_id(r: &Request) -> Option<String> {
+    let cookie_storage = r.cookies().ok()?;
+    let session_id_cookie = r.cookie(SESSION_COOKIE_NAME)?;
+    cookie_storage
+        .private_decrypt(session_id_cookie)
+        .map(|c| c.value().to_owned())
+}
+
+fn current_token(r: &Request) -> viz::Result<(AccessToken, String)> {
+    let token = r
+        .session()
+        .get::<AccessToken>(SESSION_FIELD_TOKEN)?
+        .ok_or_else(|| StatusCode::UNAUTHORIZED.into_error())?;
+    let session_id = current_session_id(r).ok_or_else(|| StatusCode::UNAUTHORIZED.into_error())?;
+    Ok((token, session_id))
 }
 
 pub async fn index(r: Request) -> viz::Result<Response> {
@@ -119,75 +400,388 @@ pub async fn index(r: Request) -> viz::Result<Response> {
         .state::<config::Config>()
         .ok_or_else(|| StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
 
-    let maybe_state = r.session().get::<String>(SESSION_FIELD_STATE)?;
+    if let Some(redirect) = upgrade_to_https(&r, &config) {
+        return Ok(redirect);
+    }
+
+    if let Ok(error) = r.query::<OAuth2Error>() {
+        if let Some(state) = &error.state {
+            discard_login_attempt(&r, state)?;
+        }
+        return show_authentication_failure(
+            &r,
+            match error.error.as_str() {
+                "access_denied" => AuthFailure::AccessDenied,
+                _ => AuthFailure::Rejected(error.describe()),
+            },
+        );
+    }
+
     let maybe_query = r.query::<OAuth2FeedbackQuery>();
 
     match maybe_query {
         Err(outer_error) => {
-            let token = r.session().get::<AccessToken>(SESSION_FIELD_TOKEN).unwrap();
+            if let Ok(query) = r.query::<ScopeQuery>() {
+                match scopes::validate(&config, scopes::parse(&query.scope)) {
+                    Err(e) => return show_authentication_error(&r, &e),
+                    Ok(requested) => r.session().set(SESSION_FIELD_REQUESTED_SCOPES, requested)?,
+                }
+            }
+            if let Ok(query) = r.query::<AppQuery>() {
+                if config.api.app(Some(&query.app)).is_none() {
+                    return show_authentication_error(
+                        &r,
+                        &format!(
+                            "there is no osu! API app named {:?} on this relay",
+                            query.app
+                        ),
+                    );
+                }
+                r.session().set(SESSION_FIELD_REQUESTED_APP, query.app)?;
+            }
+            // Any page can send the browser here, so the user has to confirm before a code is handed out.
+            if let Ok(handoff) = r.query::<HandoffQuery>() {
+                return match handoff::validate(&handoff) {
+                    Err(e) => show_authentication_error(&r, &e),
+                    Ok(url) => show_handoff_page(&r, &handoff, &url),
+                };
+            }
+
+            let token = match r.session().get::<AccessToken>(SESSION_FIELD_TOKEN) {
+                Ok(token) => token,
+                Err(_) => return show_authentication_failure(&r, AuthFailure::CorruptCookie),
+            };
             match token {
                 Some(t) => {
-                    let cookie_storage = r.cookies().unwrap();
-                    let session_id_cookie = r.cookie(SESSION_COOKIE_NAME).unwrap();
-                    let decrypted = cookie_storage.private_decrypt(session_id_cookie);
-                    show_index_with_user_data(reqwest::Client::new(), t, decrypted.unwrap().value())
-                        .await
+                    if let Some(requested) = r
+                        .session()
+                        .get::<Vec<String>>(SESSION_FIELD_REQUESTED_SCOPES)?
+                    {
+                        if !scopes::missing(&granted_scopes(&r, &config), &requested).is_empty() {
+                            return show_authentication_page(r, &config);
+                        }
+                        r.session().remove(SESSION_FIELD_REQUESTED_SCOPES);
+                    }
+                    if let Some(requested) =
+                        r.session().get::<String>(SESSION_FIELD_REQUESTED_APP)?
+                    {
+                        if requested != current_app(&r, &config).name {
+                            return show_authentication_page(r, &config);
+                        }
+                        r.session().remove(SESSION_FIELD_REQUESTED_APP);
+                    }
+
+                    let session_id = match current_session_id(&r) {
+                        Some(session_id) => session_id,
+                        None => return show_authentication_failure(&r, AuthFailure::CorruptCookie),
+                    };
+                    let user = match session_user(&r, &config, &t).await {
+                        Ok(user) => user,
+                        Err(e) => return show_authentication_failure(&r, e),
+                    };
+                    let groups = user.groups.as_deref().unwrap_or_default();
+                    if let Err(denial) = access::check_user(&config.access, user.user_id, groups) {
+                        return show_authentication_failure(&r, AuthFailure::Forbidden(denial));
+                    }
+                    if let Err(e) = track_device(&r, user.user_id, &session_id).await {
+                        return show_authentication_error(&r, &e);
+                    }
+
+                    let notice = complete_pending_pairing(&r, &session_id).await;
+                    if let Some(response) = complete_pending_handoff(&r, &session_id).await {
+                        return response;
+                    }
+                    show_index(&r, user, t, &session_id, None, notice).await
                 }
                 None => {
                     if outer_error.to_string().contains("missing field") {
                         show_authentication_page(r, &config)
                     } else {
-                        r.session().remove(SESSION_FIELD_STATE);
-                        show_authentication_error(&format!(
-                            "error while reading query string: {}",
-                            outer_error
-                        ))
+                        show_authentication_error(
+                            &r,
+                            &format!("error while reading query string: {}", outer_error),
+                        )
                     }
                 }
             }
         }
-        Ok(query) => match maybe_state {
-            None => {
-                r.session().remove(SESSION_FIELD_STATE);
-                show_authentication_page(r, &config)
-            }
-            Some(ref state) => {
-                if state != &query.state {
-                    r.session().remove(SESSION_FIELD_STATE);
-                    show_authentication_error(
-                        "local authentication state doesn't match that of osu! web -- try again",
+        Ok(query) => {
+            let attempt = match discard_login_attempt(&r, &query.state)? {
+                Some(attempt) => attempt,
+                // The attempt has expired or was already used, e.g. by reloading the page or logging in from another tab.
+                None => {
+                    return match r.session().get::<AccessToken>(SESSION_FIELD_TOKEN)? {
+                        Some(_) => Ok(Response::redirect_with_status("/auth", StatusCode::FOUND)),
+                        None => show_authentication_page(r, &config),
+                    };
+                }
+            };
+            let app = match config.api.app(Some(&attempt.app)) {
+                Some(app) => app,
+                None => {
+                    return show_authentication_error(
+                        &r,
+                        &format!(
+                            "osu! API app {:?} is no longer configured on this relay -- try again",
+                            attempt.app
+                        ),
                     )
-                } else {
-                    let client = reqwest::Client::new();
-                    match client
-                        .execute(make_authentication_request(&config, &query))
-                        .await
-                    {
-                        Err(e) => show_authentication_error(&format!(
-                            "failed to request the API token: {}",
-                            e
-                        )),
-                        Ok(response) => {
-                            let text = response.text().await.unwrap();
-                            let token: AccessToken = serde_json::from_str(&text).unwrap();
-                            match r.session().set(SESSION_FIELD_TOKEN, token) {
-                                Ok(()) => {
-                                    Ok(Response::redirect_with_status("/auth", StatusCode::FOUND))
-                                }
-                                Err(e) => show_authentication_error(&format!(
-                                    "failed to save the obtained API token: {}",
-                                    e
-                                )),
-                            }
+                }
+            };
+
+            let client = reqwest::Client::new();
+            let result = client
+                .execute(make_authentication_request(
+                    &config.api,
+                    app,
+                    &query,
+                    &attempt,
+                ))
+                .await;
+            metrics::observe_osu_api_response("token", &result);
+            match read_osu_response::<AccessToken>(result).await {
+                Err(e) => show_authentication_failure(&r, e),
+                Ok(token) => {
+                    // The token is only kept if the policy lets the user in.
+                    let user = match fetch_user_data(&config.api, &client, &token).await {
+                        Ok(user) => user,
+                        Err(e) => return show_authentication_failure(&r, e),
+                    };
+                    let session_id = match current_session_id(&r) {
+                        Some(session_id) => session_id,
+                        None => return show_authentication_failure(&r, AuthFailure::CorruptCookie),
+                    };
+                    let storage = r
+                        .state::<ValkeyStorage>()
+                        .ok_or_else(|| StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+                    match access::check_login(&config.access, &storage, &user, &session_id).await {
+                        Err(e) => {
+                            return show_authentication_error(
+                                &r,
+                                &format!("failed to check whether you may log in: {}", e),
+                            )
                         }
+                        Ok(Err(denial)) => {
+                            return show_authentication_failure(&r, AuthFailure::Forbidden(denial))
+                        }
+                        Ok(Ok(())) => {}
+                    }
+                    r.session().set(SESSION_FIELD_USER, user)?;
+                    r.session().remove(SESSION_FIELD_REQUESTED_SCOPES);
+                    r.session().set(SESSION_FIELD_SCOPES, attempt.scope)?;
+                    r.session().remove(SESSION_FIELD_REQUESTED_APP);
+                    r.session().set(SESSION_FIELD_APP, &app.name)?;
+                    if let Err(e) = refresher::schedule(&storage, &session_id, &token).await {
+                        return show_authentication_error(
+                            &r,
+                            &format!("failed to schedule the refresh of your API token: {}", e),
+                        );
+                    }
+                    match r.session().set(SESSION_FIELD_TOKEN, token) {
+                        Ok(()) => Ok(Response::redirect_with_status("/auth", StatusCode::FOUND)),
+                        Err(e) => show_authentication_error(
+                            &r,
+                            &format!("failed to save the obtained API token: {}", e),
+                        ),
                     }
                 }
             }
-        },
+        }
+    }
+}
+
+async fn complete_pending_pairing(r: &Request, session_id: &str) -> Option<&'static str> {
+    let device_code = r.session().get::<String>(SESSION_FIELD_PAIRING).ok()??;
+    r.session().remove(SESSION_FIELD_PAIRING);
+
+    let storage = r.state::<ValkeyStorage>()?;
+    match pairing::complete(&storage, &device_code, session_id).await {
+        Ok(true) => Some("Your client has been paired and will log in shortly."),
+        Ok(false) => Some("The pairing code has expired -- request a new one from your client."),
+        Err(e) => {
+            log::error!("Error while completing a pairing in Valkey: {}", e);
+            Some("Failed to pair your client -- try again later.")
+        }
+    }
+}
+
+async fn complete_pending_handoff(r: &Request, session_id: &str) -> Option<viz::Result<Response>> {
+    let handoff = r
+        .session()
+        .get::<HandoffQuery>(SESSION_FIELD_HANDOFF)
+        .ok()??;
+    r.session().remove(SESSION_FIELD_HANDOFF);
+
+    let storage = r.state::<ValkeyStorage>()?;
+    Some(match handoff::start(&storage, &handoff, session_id).await {
+        Ok(url) => Ok(Response::redirect_with_status(
+            url.as_str(),
+            StatusCode::FOUND,
+        )),
+        Err(e) => {
+            log::error!("Error while saving an exchange code to Valkey: {}", e);
+            show_authentication_error(
+                r,
+                "failed to hand the session over to your client -- try again",
+            )
+        }
+    })
+}
+
+pub async fn pairing(r: Request) -> viz::Result<Response> {
+    let user_code = r
+        .query::<PairingForm>()
+        .map(|q| q.user_code)
+        .unwrap_or_default();
+    Ok(Response::html(
+        AuthPairingPage {
+            user_code: &user_code,
+            submit_url: PAIRING_PAGE_PATH,
+            csrf_token: &middleware::csrf_token(&r),
+        }
+        .to_string(),
+    ))
+}
+
+pub async fn submit_pairing(mut r: Request) -> viz::Result<Response> {
+    let form = r.form::<PairingForm>().await?;
+    let storage = r
+        .state::<ValkeyStorage>()
+        .ok_or_else(|| StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+
+    match pairing::find(&storage, &form.user_code).await {
+        Err(e) => {
+            show_authentication_error(&r, &format!("failed to look up the pairing code: {}", e))
+        }
+        Ok(None) => show_authentication_error(
+            &r,
+            "this pairing code is unknown or has expired -- request a new one from your client",
+        ),
+        Ok(Some((device_code, p))) => {
+            if let Some(scope) = p.scope {
+                r.session().set(SESSION_FIELD_REQUESTED_SCOPES, scope)?;
+            }
+            if let Some(app) = p.app {
+                r.session().set(SESSION_FIELD_REQUESTED_APP, app)?;
+            }
+            r.session().set(SESSION_FIELD_PAIRING, device_code)?;
+            Ok(Response::redirect_with_status(
+                "/auth",
+                StatusCode::SEE_OTHER,
+            ))
+        }
+    }
+}
+
+fn show_handoff_page(
+    r: &Request,
+    handoff: &HandoffQuery,
+    url: &reqwest::Url,
+) -> viz::Result<Response> {
+    Ok(Response::html(
+        AuthHandoffPage {
+            port: url.port().unwrap_or_default(),
+            return_to: &handoff.return_to,
+            nonce: &handoff.nonce,
+            submit_url: HANDOFF_PAGE_PATH,
+            csrf_token: &middleware::csrf_token(r),
+        }
+        .to_string(),
+    ))
+}
+
+/// Remember the confirmed handoff, which is completed as soon as the session is logged in.
+pub async fn submit_handoff(mut r: Request) -> viz::Result<Response> {
+    let form = r.form::<HandoffQuery>().await?;
+    if let Err(e) = handoff::validate(&form) {
+        return show_authentication_error(&r, &e);
+    }
+    r.session().set(SESSION_FIELD_HANDOFF, form)?;
+    Ok(Response::redirect_with_status(
+        "/auth",
+        StatusCode::SEE_OTHER,
+    ))
+}
+
+pub async fn create_key(mut r: Request) -> viz::Result<Response> {
+    let form = r.form::<ApiKeyForm>().await?;
+    let (token, session_id) = current_token(&r)?;
+    let config = r
+        .state::<config::Config>()
+        .ok_or_else(|| StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+    let storage = r
+        .state::<ValkeyStorage>()
+        .ok_or_else(|| StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+
+    let user = match session_user(&r, &config, &token).await {
+        Ok(user) => user,
+        Err(e) => return show_authentication_failure(&r, e),
+    };
+
+    match api_keys::create(&storage, &session_id, form.label.as_deref()).await {
+        Err(e) => show_authentication_error(&r, &format!("failed to create an API key: {}", e)),
+        Ok(key) => show_index(&r, user, token, &session_id, Some(&key), None).await,
+    }
+}
+
+pub async fn revoke_key(mut r: Request) -> viz::Result<Response> {
+    let form = r.form::<ApiKeyRevocationForm>().await?;
+    let (_, session_id) = current_token(&r)?;
+    let storage = r
+        .state::<ValkeyStorage>()
+        .ok_or_else(|| StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+
+    match api_keys::revoke(&storage, &session_id, &form.id).await {
+        Err(e) => show_authentication_error(&r, &format!("failed to revoke the API key: {}", e)),
+        Ok(_) => Ok(Response::redirect_with_status(
+            "/auth",
+            StatusCode::SEE_OTHER,
+        )),
+    }
+}
+
+pub async fn revoke_device(mut r: Request) -> viz::Result<Response> {
+    let form = r.form::<DeviceRevocationForm>().await?;
+    current_token(&r)?;
+    let user_id = r
+        .session()
+        .get::<u32>(SESSION_FIELD_USER_ID)?
+        .ok_or_else(|| StatusCode::UNAUTHORIZED.into_error())?;
+    let current_device = r.session().get::<DeviceInfo>(SESSION_FIELD_DEVICE)?;
+    if current_device.is_some_and(|device| device.id == form.id) {
+        return logout(r).await;
+    }
+
+    let storage = r
+        .state::<ValkeyStorage>()
+        .ok_or_else(|| StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+    match devices::revoke(&storage, user_id, &form.id).await {
+        Err(e) => show_authentication_error(&r, &format!("failed to log the device out: {}", e)),
+        Ok(_) => Ok(Response::redirect_with_status(
+            "/auth",
+            StatusCode::SEE_OTHER,
+        )),
     }
 }
 
 pub async fn logout(r: Request) -> viz::Result<Response> {
+    if let (Some(session_id), Some(storage)) = (current_session_id(&r), r.state::<ValkeyStorage>())
+    {
+        if let Err(e) = api_keys::revoke_all(&storage, &session_id).await {
+            log::error!("Error while revoking API keys of a closed session: {}", e);
+        }
+        if let (Ok(Some(user_id)), Ok(Some(device))) = (
+            r.session().get::<u32>(SESSION_FIELD_USER_ID),
+            r.session().get::<DeviceInfo>(SESSION_FIELD_DEVICE),
+        ) {
+            if let Err(e) = devices::forget(&storage, user_id, &device.id).await {
+                log::error!(
+                    "Error while removing a closed session from the device list: {}",
+                    e
+                );
+            }
+        }
+    }
     r.session().clear();
     Ok(Response::redirect_with_status("/auth", StatusCode::FOUND))
 }
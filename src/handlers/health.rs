@@ -0,0 +1,80 @@
+use std::collections::BTreeMap;
+
+use viz::{IntoResponse, Request, RequestExt, Response, ResponseExt, StatusCode};
+
+use crate::config::Config;
+use crate::model::{HealthCheck, HealthReport};
+use crate::refresher::{RefresherStatus, HEARTBEAT_TIMEOUT_SECS};
+use crate::storage::ValkeyStorage;
+
+fn respond(checks: BTreeMap<String, HealthCheck>) -> viz::Result<Response> {
+    let ok = checks.values().all(|c| c.ok);
+    let mut response = Response::json(HealthReport { ok, checks }).unwrap();
+    if !ok {
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+    Ok(response)
+}
+
+pub async fn healthz(_r: Request) -> viz::Result<Response> {
+    respond(BTreeMap::new())
+}
+
+pub async fn readyz(r: Request) -> viz::Result<Response> {
+    let mut checks = BTreeMap::new();
+
+    checks.insert(
+        "config".to_owned(),
+        match r.state::<Config>() {
+            Some(c) => HealthCheck {
+                ok: true,
+                detail: format!("{} osu! API app(s)", c.api.apps.len()),
+            },
+            None => HealthCheck {
+                ok: false,
+                detail: "not loaded".to_owned(),
+            },
+        },
+    );
+
+    let storage = r
+        .state::<ValkeyStorage>()
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+    checks.insert(
+        "valkey".to_owned(),
+        match storage.ping().await {
+            Ok(()) => HealthCheck {
+                ok: true,
+                detail: "reachable".to_owned(),
+            },
+            Err(e) => HealthCheck {
+                ok: false,
+                detail: e.to_string(),
+            },
+        },
+    );
+
+    let status = r
+        .state::<RefresherStatus>()
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR.into_error())?;
+    let detail = match status.last_sweep() {
+        Some(ts) => format!("last sweep finished at {}", ts),
+        None => "no sweep finished yet".to_owned(),
+    };
+    checks.insert(
+        "refresher".to_owned(),
+        HealthCheck {
+            ok: status.is_alive(),
+            detail: if status.is_alive() {
+                detail
+            } else {
+                format!(
+                    "{} (considered stale after {}s)",
+                    detail, HEARTBEAT_TIMEOUT_SECS
+                )
+            },
+        },
+    );
+
+    respond(checks)
+}
@@ -0,0 +1,13 @@
+use viz::{IntoResponse, Request, Response, ResponseExt, StatusCode};
+
+use crate::metrics;
+
+pub async fn metrics(_r: Request) -> viz::Result<Response> {
+    match metrics::render() {
+        Ok(text) => Ok(Response::text(text)),
+        Err(e) => {
+            log::error!("Failed to render metrics: {}", e);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
@@ -1,3 +1,7 @@
+pub mod admin;
 pub mod api;
+pub mod assets;
 pub mod auth;
+pub mod health;
 pub mod index;
+pub mod metrics;
@@ -0,0 +1,98 @@
+use chrono::Utc;
+use nanoid::nanoid;
+
+use crate::api_keys;
+use crate::model::{ExchangeCode, HandoffQuery, NewApiKey};
+use crate::secrets;
+use crate::storage::{ValkeyStorage, EXCHANGE_KEY_PREFIX};
+
+pub const HANDOFF_PAGE_PATH: &str = "/auth/handoff";
+pub const EXCHANGE_CODE_LIFETIME_SECS: u64 = 60;
+
+const EXCHANGE_CODE_LENGTH: usize = 32;
+const MIN_NONCE_LENGTH: usize = 16;
+const MAX_NONCE_LENGTH: usize = 128;
+const LOOPBACK_HOSTS: [&str; 2] = ["127.0.0.1", "localhost"];
+
+fn exchange_key(code: &str) -> String {
+    format!("{}{}", EXCHANGE_KEY_PREFIX, code)
+}
+
+/// Make sure that the browser is only ever sent back to a client listening on the same machine.
+pub fn validate(query: &HandoffQuery) -> Result<reqwest::Url, String> {
+    let url = reqwest::Url::parse(&query.return_to)
+        .map_err(|e| format!("the return address is not a valid URL: {}", e))?;
+
+    if url.scheme() != "http" {
+        return Err("the return address must use plain http".to_owned());
+    }
+    if !url
+        .host_str()
+        .is_some_and(|host| LOOPBACK_HOSTS.contains(&host))
+    {
+        return Err("the return address must point to 127.0.0.1 or localhost".to_owned());
+    }
+    if url.port().is_none() {
+        return Err("the return address must include a port".to_owned());
+    }
+    if !url.username().is_empty() || url.password().is_some() || url.fragment().is_some() {
+        return Err("the return address must not contain credentials or a fragment".to_owned());
+    }
+
+    if !(MIN_NONCE_LENGTH..=MAX_NONCE_LENGTH).contains(&query.nonce.len())
+        || !query
+            .nonce
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!(
+            "the client nonce must be {}-{} characters long and URL-safe",
+            MIN_NONCE_LENGTH, MAX_NONCE_LENGTH
+        ));
+    }
+
+    Ok(url)
+}
+
+/// Issue a one-time code for the session and build the address the browser should be sent to.
+pub async fn start(
+    storage: &ValkeyStorage,
+    handoff: &HandoffQuery,
+    session_id: &str,
+) -> eyre::Result<reqwest::Url> {
+    let mut url = reqwest::Url::parse(&handoff.return_to)?;
+    let code = nanoid!(EXCHANGE_CODE_LENGTH);
+    storage
+        .set_json(
+            &exchange_key(&code),
+            &ExchangeCode {
+                session_id: session_id.to_owned(),
+                nonce: handoff.nonce.clone(),
+                ctime: Utc::now().timestamp(),
+            },
+            Some(EXCHANGE_CODE_LIFETIME_SECS),
+        )
+        .await?;
+
+    url.query_pairs_mut().append_pair("code", &code);
+    Ok(url)
+}
+
+/// Trade a code for an API key to the session it was issued for. The code is burned on the first attempt, matching or not.
+pub async fn exchange(
+    storage: &ValkeyStorage,
+    code: &str,
+    nonce: &str,
+    label: Option<&str>,
+) -> eyre::Result<Option<NewApiKey>> {
+    match storage
+        .take_json::<ExchangeCode>(&exchange_key(code))
+        .await?
+        .filter(|exchange| secrets::constant_time_eq(&exchange.nonce, nonce))
+    {
+        Some(exchange) => Ok(Some(
+            api_keys::create(storage, &exchange.session_id, label).await?,
+        )),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,60 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use nanoid::nanoid;
+use sha2::{Digest, Sha256};
+
+use crate::config::App;
+use crate::model::LoginAttempt;
+use crate::secrets;
+
+/// How long the user has to come back from osu! web before the attempt is discarded.
+pub const LOGIN_ATTEMPT_LIFETIME_SECS: i64 = 10 * 60;
+/// How many logins may be in flight for the same session, e.g. from several tabs.
+pub const MAX_PENDING_LOGIN_ATTEMPTS: usize = 5;
+
+const STATE_LENGTH: usize = 32;
+// RFC 7636 allows 43-128 characters, all of which nanoid's default alphabet satisfies.
+const CODE_VERIFIER_LENGTH: usize = 64;
+
+pub fn start(app: &App, scope: Vec<String>) -> LoginAttempt {
+    LoginAttempt {
+        state: nanoid!(STATE_LENGTH),
+        code_verifier: nanoid!(CODE_VERIFIER_LENGTH),
+        app: app.name.clone(),
+        scope,
+        ctime: Utc::now().timestamp(),
+    }
+}
+
+/// The S256 PKCE challenge derived from the attempt's verifier.
+pub fn code_challenge(attempt: &LoginAttempt) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(attempt.code_verifier.as_bytes()))
+}
+
+fn prune(attempts: &mut Vec<LoginAttempt>) {
+    let now = Utc::now().timestamp();
+    attempts.retain(|a| now - a.ctime <= LOGIN_ATTEMPT_LIFETIME_SECS);
+}
+
+/// Add an attempt to the pending ones, making room by forgetting the oldest.
+pub fn remember(attempts: &mut Vec<LoginAttempt>, attempt: LoginAttempt) {
+    prune(attempts);
+    attempts.push(attempt);
+    if attempts.len() > MAX_PENDING_LOGIN_ATTEMPTS {
+        attempts.drain(..attempts.len() - MAX_PENDING_LOGIN_ATTEMPTS);
+    }
+}
+
+/// Find the pending attempt which issued `state` and remove it, so that it can't be replayed.
+pub fn take(attempts: &mut Vec<LoginAttempt>, state: &str) -> Option<LoginAttempt> {
+    prune(attempts);
+    // Check every attempt, so that timing doesn't reveal which one matched.
+    let position = attempts
+        .iter()
+        .map(|a| secrets::constant_time_eq(&a.state, state))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .position(|matches| matches)?;
+    Some(attempts.remove(position))
+}
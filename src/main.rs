@@ -1,10 +1,14 @@
+#![allow(clippy::result_large_err)]
+
 use std::fmt::Write;
 use std::net::Ipv4Addr;
 use std::net::SocketAddr;
 use std::str::FromStr;
+use std::time::Duration;
 
 use eyre::Result;
-use refresher::TokenRefresher;
+use refresher::{RefresherStatus, TokenRefresher};
+use secrets::Secret;
 use storage::ValkeyStorage;
 use storage::SESSION_COOKIE_NAME;
 use tokio::net::TcpListener;
@@ -12,23 +16,69 @@ use tokio::net::TcpListener;
 use viz::types::State;
 use viz::{
     middleware::{
-        cookie,
+        cookie, csrf,
         helper::CookieOptions,
         session::{self, Store},
     },
-    types::CookieKey,
+    types::{CookieKey, SameSite},
 };
-use viz::{serve, Router};
+use viz::{serve, Method, Router};
 
+pub mod access;
+pub mod admin;
+pub mod api_keys;
+pub mod app_tokens;
 pub mod config;
+pub mod devices;
 pub mod handlers;
+pub mod handoff;
+pub mod login;
+pub mod metrics;
 pub mod middleware;
 pub mod model;
+pub mod pairing;
 pub mod refresher;
+pub mod scopes;
+pub mod secrets;
 pub mod storage;
 pub mod templates;
 
 const DEFAULT_CONFIG_PATH: &str = "./config.yaml";
+const CONFIG_PATH_VARIABLE: &str = "RELAY_CONFIG";
+
+const HELP: &str = "\
+Usage: relay [--config <path>]
+
+Stores osu! API tokens for steel and refreshes them in the background.
+
+Options:
+  -c, --config <path>  read the config from <path> (default: $RELAY_CONFIG, or ./config.yaml)
+  -h, --help           print this message
+
+Any setting of the config file can be overridden with an environment variable: RELAY_, followed
+by the names of its sections and its own name in upper case, separated with double underscores.
+Items of lists are numbered from 0:
+
+  RELAY_SERVICE__BIND_PORT=8080
+  RELAY_SERVICE__VALKEY__ADDRESS=redis://valkey:6379
+  RELAY_API__APPS__0__CLIENT_SECRET=...
+  RELAY_ADMIN__USER_IDS='[2, 3]'
+
+Settings which are text, such as secrets and URLs, are taken as they are. Everything else is read
+as YAML, e.g. numbers, true or false, and [lists]. A variable starting with RELAY_ and containing
+double underscores which doesn't name a setting is an error.
+
+Secrets may be read from files, such as Docker or Kubernetes secrets, by adding _file to the name
+of a setting: cookie_key_file: /run/secrets/cookie-key in the config file, or
+RELAY_SERVICE__COOKIE_KEY_FILE=/run/secrets/cookie-key in the environment. Trailing line breaks
+are dropped.
+
+Settings are taken from, in order of increasing precedence:
+  1. built-in defaults
+  2. the config file, where a setting and its _file variant can't be used together
+  3. environment variables, which replace both variants of the setting from the config file
+     (RELAY_X and RELAY_X_FILE can't be used together either)
+";
 
 fn bin2hex(bytes: &[u8]) -> String {
     bytes.iter().fold(String::new(), |mut output, b| {
@@ -44,6 +94,31 @@ fn hex2bin(hex: &str) -> Vec<u8> {
         .collect()
 }
 
+/// The config path from the command line or the environment, or `None` if only the usage was asked for.
+fn config_path() -> Result<Option<String>> {
+    let mut path = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-c" | "--config" => {
+                path = Some(
+                    args.next()
+                        .ok_or_else(|| eyre::eyre!("{} needs a path -- see --help", arg))?,
+                )
+            }
+            _ => match arg.strip_prefix("--config=") {
+                Some(value) => path = Some(value.to_owned()),
+                None => eyre::bail!("unknown argument {:?} -- see --help", arg),
+            },
+        }
+    }
+    Ok(Some(
+        path.or_else(|| std::env::var(CONFIG_PATH_VARIABLE).ok())
+            .unwrap_or_else(|| DEFAULT_CONFIG_PATH.to_owned()),
+    ))
+}
+
 pub fn generate_session_id() -> String {
     nanoid::nanoid!(64)
 }
@@ -52,47 +127,131 @@ pub fn verify_session_id(sid: &str) -> bool {
     sid.len() == 64
 }
 
+fn session_cookie_options(c: &config::Cookie) -> CookieOptions {
+    // viz wants the attributes for the whole lifetime of the server.
+    let mut options = CookieOptions::new(SESSION_COOKIE_NAME)
+        .secure(c.secure)
+        .path(c.path.clone().leak())
+        .same_site(match c.same_site {
+            config::SameSite::Strict => SameSite::Strict,
+            config::SameSite::Lax => SameSite::Lax,
+            config::SameSite::None => SameSite::None,
+        });
+    if let Some(domain) = &c.domain {
+        options = options.domain(domain.clone().leak());
+    }
+    options.max_age = c.max_age_secs().map(Duration::from_secs);
+    options
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    let Some(config_path) = config_path()? else {
+        print!("{}", HELP);
+        return Ok(());
+    };
     env_logger::init();
 
-    let mut c = config::Config::load(DEFAULT_CONFIG_PATH)?;
+    let mut c = config::Config::load(&config_path)?;
     let bind_ip = Ipv4Addr::from_str(&c.service.bind_host)?;
     let addr = SocketAddr::from((bind_ip, c.service.bind_port));
 
     let listener = TcpListener::bind(addr).await?;
 
     let key = match c.service.cookie_key {
-        Some(ref k) => CookieKey::from(&hex2bin(k)),
+        Some(ref k) => CookieKey::from(&hex2bin(k.expose())),
         None => {
             let key = CookieKey::generate();
-            c.service.cookie_key = Some(bin2hex(key.master()));
-            c.save(DEFAULT_CONFIG_PATH)?;
+            let generated = Secret::new(bin2hex(key.master()));
+            config::Config::save_cookie_key(&config_path, &generated)?;
+            c.service.cookie_key = Some(generated);
             key
         }
     };
 
+    if let Some(theme_dir) = &c.service.theme_dir {
+        templates::load_theme(theme_dir)?;
+    }
+
     let storage = storage::ValkeyStorage::new(&c);
+    let refresher_status = RefresherStatus::default();
+
+    // Everything a page can do with a POST needs the token rendered into its forms.
+    let csrf = csrf::Config::new(
+        csrf::Store::Session,
+        [Method::GET, Method::HEAD, Method::OPTIONS, Method::TRACE].into(),
+        CookieOptions::new(middleware::CSRF_SESSION_FIELD),
+        csrf::secret,
+        csrf::generate,
+        csrf::verify,
+    );
 
     let app = Router::new()
         .get("/", handlers::index::index)
+        .get("/healthz", handlers::health::healthz)
+        .get("/readyz", handlers::health::readyz)
+        .get("/metrics", handlers::metrics::metrics)
+        .get("/static/:name", handlers::assets::asset)
         .nest(
             "/auth",
             Router::new()
                 .get("/", handlers::auth::index)
-                .get("/logout", handlers::auth::logout),
+                .get("/pair", handlers::auth::pairing)
+                .post("/pair", handlers::auth::submit_pairing)
+                .post("/handoff", handlers::auth::submit_handoff)
+                .post("/keys", handlers::auth::create_key)
+                .post("/keys/revoke", handlers::auth::revoke_key)
+                .post("/devices/revoke", handlers::auth::revoke_device)
+                .post("/logout", handlers::auth::logout)
+                .with(csrf.clone())
+                .with(middleware::CsrfFormConfig),
+        )
+        .nest(
+            "/admin",
+            Router::new()
+                .get("/", handlers::admin::dashboard)
+                .get("/sessions/:id", handlers::admin::session)
+                .post("/sessions/:id/refresh", handlers::admin::refresh_session)
+                .post("/sessions/:id/revoke", handlers::admin::revoke_session)
+                .with(csrf)
+                .with(middleware::CsrfFormConfig),
         )
         .get("/api/token", handlers::api::token)
-        .with(middleware::Config::new(c.service.max_concurrent_requests))
+        .get("/api/app-token", handlers::api::app_token)
+        .get("/api/me", handlers::api::me)
+        .get("/api/keys", handlers::api::list_keys)
+        .post("/api/keys", handlers::api::create_key)
+        .delete("/api/keys/:id", handlers::api::revoke_key)
+        .get("/api/devices", handlers::api::list_devices)
+        .delete("/api/devices/:id", handlers::api::revoke_device)
+        .post("/api/pairing", handlers::api::start_pairing)
+        .post("/api/pairing/poll", handlers::api::poll_pairing)
+        .post("/api/exchange", handlers::api::exchange)
+        .with(middleware::ConcurrencyLimitConfig::new(
+            c.service.max_concurrent_requests,
+            &c.service.rate_limit,
+        ))
+        .with(middleware::RateLimitConfig::new(&c.service.rate_limit))
+        .with(middleware::MetricsConfig)
+        .with(middleware::SecurityHeadersConfig::new(
+            &c.service.security_headers,
+            c.cookie.secure,
+        )?)
+        .with(middleware::AccessLogConfig)
+        .with(middleware::ClientInfoConfig::new(
+            c.service.trusted_proxies()?,
+            c.service.forwarded_header,
+        ))
         .with(State::<config::Config>::new(c.clone()))
         .with(State::<ValkeyStorage>::new(storage.clone()))
+        .with(State::<RefresherStatus>::new(refresher_status.clone()))
         .with(session::Config::new(
             Store::new(storage.clone(), generate_session_id, verify_session_id),
-            CookieOptions::default().name(SESSION_COOKIE_NAME),
+            session_cookie_options(&c.cookie),
         ))
         .with(cookie::Config::with_key(key));
 
-    let mut refresher = TokenRefresher::new(c, storage);
+    let mut refresher = TokenRefresher::new(c, storage, refresher_status);
     refresher.start();
 
     serve(listener, app).await?;
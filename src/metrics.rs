@@ -0,0 +1,104 @@
+use std::sync::LazyLock;
+
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter_vec, register_int_gauge,
+    Encoder, Histogram, HistogramVec, IntCounterVec, IntGauge, TextEncoder,
+};
+
+pub static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "relay_http_requests_total",
+        "HTTP requests handled, by route and response status",
+        &["route", "method", "status"]
+    )
+    .unwrap()
+});
+
+pub static HTTP_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "relay_http_request_duration_seconds",
+        "Time spent handling HTTP requests, by route",
+        &["route", "method"]
+    )
+    .unwrap()
+});
+
+pub static HTTP_REQUESTS_IN_FLIGHT: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "relay_http_requests_in_flight",
+        "HTTP requests currently being handled"
+    )
+    .unwrap()
+});
+
+pub static RATE_LIMITER_REJECTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "relay_rate_limiter_rejections_total",
+        "HTTP requests turned away by the rate limiter, by reason (busy, queue_timeout, ip, session)",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+pub static REFRESHER_SWEEP_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "relay_refresher_sweep_duration_seconds",
+        "Time taken by a full sweep of the token refresher",
+        vec![1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 1800.0, 3600.0]
+    )
+    .unwrap()
+});
+
+pub static REFRESHER_SESSIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "relay_refresher_sessions_total",
+        "Sessions processed by the token refresher, by outcome (scanned, refreshed, failed -- which also counts app tokens that couldn't be renewed)",
+        &["outcome"]
+    )
+    .unwrap()
+});
+
+pub static OSU_API_RESPONSES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "relay_osu_api_responses_total",
+        "Responses received from the osu! API, by endpoint and status (\"error\" if there was none)",
+        &["endpoint", "status"]
+    )
+    .unwrap()
+});
+
+pub static SESSION_LOOKUPS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "relay_session_lookups_total",
+        "Sessions looked up in Valkey, by whether they were still there (hit) or had expired or been revoked (miss)",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static VALKEY_COMMAND_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "relay_valkey_command_duration_seconds",
+        "Latency of Valkey commands",
+        &["command"],
+        vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]
+    )
+    .unwrap()
+});
+
+/// Count a response from the osu! API, or the lack thereof.
+pub fn observe_osu_api_response(endpoint: &str, result: &reqwest::Result<reqwest::Response>) {
+    let status = match result {
+        Ok(response) => response.status().as_u16().to_string(),
+        Err(_) => "error".to_owned(),
+    };
+    OSU_API_RESPONSES
+        .with_label_values(&[endpoint, &status])
+        .inc();
+}
+
+pub fn render() -> eyre::Result<String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
@@ -1,29 +1,127 @@
-use std::sync::{Arc, Mutex};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
-use viz::{async_trait, Handler, IntoResponse, Request, Response, Result, StatusCode, Transform};
+use http_body_util::{BodyExt, Full};
+use ipnet::IpNet;
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+use viz::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER};
+use viz::middleware::csrf::CsrfToken;
+use viz::types::{RouteInfo, Session};
+use viz::{
+    async_trait, Body, BodyState, Bytes, Handler, IntoResponse, Method, Request, RequestExt,
+    Response, Result, StatusCode, Transform,
+};
 
+use crate::api_keys;
+use crate::config::{ForwardedHeader, RateLimit, SecurityHeaders};
+use crate::handlers::api::API_KEY_HEADER_NAME;
+use crate::handlers::auth::current_session_id;
+use crate::handlers::auth::SESSION_FIELD_USER_ID;
+use crate::metrics;
+use crate::secrets;
+use crate::storage::{ValkeyStorage, RATE_LIMIT_PREFIX};
+
+const RETRY_AFTER_BUSY_SECS: u64 = 1;
+
+fn too_busy(reason: &str) -> Response {
+    metrics::RATE_LIMITER_REJECTIONS
+        .with_label_values(&[reason])
+        .inc();
+    let mut resp = StatusCode::SERVICE_UNAVAILABLE.into_response();
+    resp.headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(RETRY_AFTER_BUSY_SECS));
+    resp
+}
+
+/// Caps the number of requests handled at once, letting a limited number of others wait for their turn.
 #[derive(Debug, Clone)]
-pub struct Config {
-    max_concurrent_requests: i32,
+pub struct ConcurrencyLimitConfig {
+    /// Requests being handled or waiting -- a permit is needed to even get in line.
+    admission: Arc<Semaphore>,
+    slots: Arc<Semaphore>,
+    queue_timeout: Duration,
 }
 
-impl Config {
-    pub fn new(max_concurrent_requests: i32) -> Self {
+impl ConcurrencyLimitConfig {
+    pub fn new(max_concurrent_requests: usize, config: &RateLimit) -> Self {
         Self {
-            max_concurrent_requests,
+            admission: Arc::new(Semaphore::new(
+                max_concurrent_requests + config.max_queued_requests,
+            )),
+            slots: Arc::new(Semaphore::new(max_concurrent_requests)),
+            queue_timeout: Duration::from_millis(config.queue_timeout_ms),
         }
     }
 }
 
-impl Default for Config {
-    fn default() -> Self {
+impl<H> Transform<H> for ConcurrencyLimitConfig
+where
+    H: Clone,
+{
+    type Output = ConcurrencyLimiter<H>;
+
+    fn transform(&self, h: H) -> Self::Output {
+        ConcurrencyLimiter {
+            h,
+            config: self.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimiter<H> {
+    h: H,
+    config: ConcurrencyLimitConfig,
+}
+
+#[async_trait]
+impl<H, O> Handler<Request> for ConcurrencyLimiter<H>
+where
+    H: Handler<Request, Output = Result<O>>,
+    O: IntoResponse,
+{
+    type Output = Result<Response>;
+
+    async fn call(&self, req: Request) -> Self::Output {
+        // Permits are given back when dropped, even if the handler panics.
+        let Ok(_admitted) = self.config.admission.clone().try_acquire_owned() else {
+            return Ok(too_busy("busy"));
+        };
+        let _slot = match timeout(
+            self.config.queue_timeout,
+            self.config.slots.clone().acquire_owned(),
+        )
+        .await
+        {
+            Ok(Ok(slot)) => slot,
+            _ => return Ok(too_busy("queue_timeout")),
+        };
+
+        self.h.call(req).await.map(IntoResponse::into_response)
+    }
+}
+
+/// Monitoring probes shouldn't be turned away for polling often.
+const RATE_LIMIT_EXEMPT_PATHS: [&str; 3] = ["/healthz", "/readyz", "/metrics"];
+
+/// Limits how many requests each client IP and each session (or API key) may make, across all replicas.
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    config: RateLimit,
+}
+
+impl RateLimitConfig {
+    pub fn new(config: &RateLimit) -> Self {
         Self {
-            max_concurrent_requests: 10,
+            config: config.clone(),
         }
     }
 }
 
-impl<H> Transform<H> for Config
+impl<H> Transform<H> for RateLimitConfig
 where
     H: Clone,
 {
@@ -33,7 +131,6 @@ where
         RateLimiter {
             h,
             config: self.clone(),
-            concurrent_requests: Arc::default(),
         }
     }
 }
@@ -41,8 +138,78 @@ where
 #[derive(Debug, Clone)]
 pub struct RateLimiter<H> {
     h: H,
-    config: Config,
-    concurrent_requests: Arc<Mutex<i32>>,
+    config: RateLimitConfig,
+}
+
+/// The address of the connection's other end. viz's `remote_addr()` looks it up under a different type than it stores.
+pub fn peer_addr(req: &Request) -> Option<SocketAddr> {
+    req.extensions()
+        .get::<Option<Arc<SocketAddr>>>()
+        .cloned()
+        .flatten()
+        .map(|addr| *addr)
+}
+
+/// The session a request provably acts for: the owner of its API key, or the one in its encrypted cookie if it's still around.
+/// Credentials which don't check out get no bucket of their own, or making up new ones would get a fresh bucket every time.
+/// Buckets are named after fingerprints, since session ids are bearer credentials.
+async fn session_fingerprint(
+    req: &Request,
+    storage: &ValkeyStorage,
+) -> eyre::Result<Option<String>> {
+    let owner = match req.header::<_, String>(API_KEY_HEADER_NAME) {
+        Some(key) => api_keys::owner(storage, &key).await?,
+        None => None,
+    };
+    let session_id = match owner {
+        Some(owner) => Some(owner.session_id),
+        None => current_session_id(req).filter(|_| {
+            req.extensions()
+                .get::<Session>()
+                .and_then(|session| session.data().ok())
+                .is_some_and(|data| !data.is_empty())
+        }),
+    };
+    Ok(session_id.map(|id| secrets::fingerprint(&id)))
+}
+
+/// Seconds until the client may try again, if it has run out of requests.
+async fn exhausted_bucket(
+    req: &Request,
+    config: &RateLimit,
+) -> eyre::Result<Option<(&'static str, u64)>> {
+    let storage = match req.state::<ValkeyStorage>() {
+        Some(storage) => storage,
+        None => return Ok(None),
+    };
+
+    let buckets = [
+        (
+            "ip",
+            config.per_ip.as_ref(),
+            client_ip(req).map(|ip| ip.to_string()),
+        ),
+        (
+            "session",
+            config.per_session.as_ref(),
+            match config.per_session {
+                Some(_) => session_fingerprint(req, &storage).await?,
+                None => None,
+            },
+        ),
+    ];
+    for (kind, bucket, id) in buckets {
+        if let (Some(bucket), Some(id)) = (bucket, id) {
+            let key = format!("{}{}:{}", RATE_LIMIT_PREFIX, kind, id);
+            if let Some(wait) = storage
+                .take_token(&key, bucket.burst, bucket.per_second)
+                .await?
+            {
+                return Ok(Some((kind, wait)));
+            }
+        }
+    }
+    Ok(None)
 }
 
 #[async_trait]
@@ -54,18 +221,779 @@ where
     type Output = Result<Response>;
 
     async fn call(&self, req: Request) -> Self::Output {
-        {
-            let mut current_value = self.concurrent_requests.lock().unwrap();
-            if *current_value > self.config.max_concurrent_requests {
-                return Ok(StatusCode::SERVICE_UNAVAILABLE.into_response());
+        if !RATE_LIMIT_EXEMPT_PATHS.contains(&req.path()) {
+            match exhausted_bucket(&req, &self.config.config).await {
+                Ok(None) => {}
+                Ok(Some((kind, wait))) => {
+                    metrics::RATE_LIMITER_REJECTIONS
+                        .with_label_values(&[kind])
+                        .inc();
+                    let mut resp =
+                        (StatusCode::TOO_MANY_REQUESTS, "too many requests").into_response();
+                    resp.headers_mut()
+                        .insert(RETRY_AFTER, HeaderValue::from(wait));
+                    return Ok(resp);
+                }
+                // Better to let everyone through than no one while Valkey is unavailable.
+                Err(e) => log::error!("Failed to check rate limits in Valkey: {}", e),
             }
-            *current_value += 1;
         }
 
-        let resp = self.h.call(req).await.map(IntoResponse::into_response);
-        {
-            *self.concurrent_requests.lock().unwrap() -= 1;
+        self.h.call(req).await.map(IntoResponse::into_response)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MetricsConfig;
+
+impl<H> Transform<H> for MetricsConfig
+where
+    H: Clone,
+{
+    type Output = RequestMetrics<H>;
+
+    fn transform(&self, h: H) -> Self::Output {
+        RequestMetrics { h }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestMetrics<H> {
+    h: H,
+}
+
+// Keeps the gauge right even if the handler panics.
+struct InFlightGuard;
+
+impl InFlightGuard {
+    fn new() -> Self {
+        metrics::HTTP_REQUESTS_IN_FLIGHT.inc();
+        Self
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        metrics::HTTP_REQUESTS_IN_FLIGHT.dec();
+    }
+}
+
+#[async_trait]
+impl<H, O> Handler<Request> for RequestMetrics<H>
+where
+    H: Handler<Request, Output = Result<O>>,
+    O: IntoResponse,
+{
+    type Output = Result<Response>;
+
+    async fn call(&self, req: Request) -> Self::Output {
+        let route = req
+            .extensions()
+            .get::<Arc<RouteInfo>>()
+            .map(|info| info.pattern.clone())
+            .unwrap_or_default();
+        let method = req.method().to_string();
+
+        let _in_flight = InFlightGuard::new();
+        let timer = metrics::HTTP_REQUEST_DURATION
+            .with_label_values(&[&route, &method])
+            .start_timer();
+        let resp = self
+            .h
+            .call(req)
+            .await
+            .map_or_else(IntoResponse::into_response, IntoResponse::into_response);
+        timer.observe_duration();
+
+        metrics::HTTP_REQUESTS
+            .with_label_values(&[&route, &method, resp.status().as_str()])
+            .inc();
+        Ok(resp)
+    }
+}
+
+/// Only the built-in stylesheets are allowed; images may come from osu! (avatars) over HTTPS.
+const HTML_CONTENT_SECURITY_POLICY: &str = "default-src 'none'; style-src 'self'; img-src 'self' https:; form-action 'self'; frame-ancestors 'none'; base-uri 'none'";
+const API_CONTENT_SECURITY_POLICY: &str = "default-src 'none'; frame-ancestors 'none'";
+
+const DEFAULT_HTML_HEADERS: [(&str, &str); 5] = [
+    ("content-security-policy", HTML_CONTENT_SECURITY_POLICY),
+    ("x-frame-options", "DENY"),
+    ("referrer-policy", "no-referrer"),
+    ("x-content-type-options", "nosniff"),
+    ("cache-control", "no-store"),
+];
+
+const DEFAULT_API_HEADERS: [(&str, &str); 4] = [
+    ("content-security-policy", API_CONTENT_SECURITY_POLICY),
+    ("referrer-policy", "no-referrer"),
+    ("x-content-type-options", "nosniff"),
+    ("cache-control", "no-store"),
+];
+
+/// Sent along with the defaults when cookies are secure, since relay is then only meant to be reached over HTTPS.
+const STRICT_TRANSPORT_SECURITY: (&str, &str) = ("strict-transport-security", "max-age=31536000");
+
+type HeaderList = Arc<Vec<(HeaderName, HeaderValue)>>;
+
+fn build_headers(
+    defaults: &[(&str, &str)],
+    https_only: bool,
+    overrides: &BTreeMap<String, String>,
+) -> eyre::Result<HeaderList> {
+    let mut merged = defaults
+        .iter()
+        .chain(https_only.then_some(&STRICT_TRANSPORT_SECURITY))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect::<BTreeMap<_, _>>();
+    for (k, v) in overrides {
+        merged.insert(k.to_lowercase(), v.clone());
+    }
+
+    let mut headers = Vec::with_capacity(merged.len());
+    for (k, v) in merged.into_iter().filter(|(_, v)| !v.is_empty()) {
+        headers.push((
+            HeaderName::try_from(k.as_str())
+                .map_err(|e| eyre::eyre!("invalid header name {:?}: {}", k, e))?,
+            HeaderValue::try_from(v.as_str())
+                .map_err(|e| eyre::eyre!("invalid value of header {:?}: {}", k, e))?,
+        ));
+    }
+    Ok(Arc::new(headers))
+}
+
+#[derive(Debug, Clone)]
+pub struct SecurityHeadersConfig {
+    html: HeaderList,
+    api: HeaderList,
+}
+
+impl SecurityHeadersConfig {
+    pub fn new(config: &SecurityHeaders, https_only: bool) -> eyre::Result<Self> {
+        Ok(Self {
+            html: build_headers(&DEFAULT_HTML_HEADERS, https_only, &config.html)?,
+            api: build_headers(&DEFAULT_API_HEADERS, https_only, &config.api)?,
+        })
+    }
+}
+
+impl<H> Transform<H> for SecurityHeadersConfig
+where
+    H: Clone,
+{
+    type Output = SecurityHeadersMiddleware<H>;
+
+    fn transform(&self, h: H) -> Self::Output {
+        SecurityHeadersMiddleware {
+            h,
+            config: self.clone(),
         }
-        resp
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SecurityHeadersMiddleware<H> {
+    h: H,
+    config: SecurityHeadersConfig,
+}
+
+#[async_trait]
+impl<H, O> Handler<Request> for SecurityHeadersMiddleware<H>
+where
+    H: Handler<Request, Output = Result<O>>,
+    O: IntoResponse,
+{
+    type Output = Result<Response>;
+
+    async fn call(&self, req: Request) -> Self::Output {
+        let mut resp = self
+            .h
+            .call(req)
+            .await
+            .map_or_else(IntoResponse::into_response, IntoResponse::into_response);
+
+        // Pages are told apart from everything else by what they are, not by where they live.
+        let is_html = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("text/html"));
+        let headers = if is_html {
+            &self.config.html
+        } else {
+            &self.config.api
+        };
+
+        // Handlers which set any of these themselves (e.g. caching of static assets) know better.
+        let response_headers = resp.headers_mut();
+        for (name, value) in headers.iter() {
+            if !response_headers.contains_key(name) {
+                response_headers.insert(name.clone(), value.clone());
+            }
+        }
+        Ok(resp)
+    }
+}
+
+/// Session field holding the CSRF secret.
+pub const CSRF_SESSION_FIELD: &str = "csrf";
+/// Hidden field carrying the CSRF token in the forms of relay's pages.
+pub const CSRF_FORM_FIELD: &str = "csrf_token";
+const CSRF_HEADER: &str = "x-csrf-token";
+
+/// The token to put into the forms of a page, empty on routes without CSRF protection.
+pub fn csrf_token(r: &Request) -> String {
+    r.extensions()
+        .get::<CsrfToken>()
+        .map(|token| token.0.clone())
+        .unwrap_or_default()
+}
+
+/// viz's CSRF middleware only looks for the token in a header, which plain HTML forms can't send.
+/// This copies it over from the form, and has to wrap the CSRF middleware.
+#[derive(Debug, Clone, Default)]
+pub struct CsrfFormConfig;
+
+impl<H> Transform<H> for CsrfFormConfig
+where
+    H: Clone,
+{
+    type Output = CsrfFormField<H>;
+
+    fn transform(&self, h: H) -> Self::Output {
+        CsrfFormField { h }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CsrfFormField<H> {
+    h: H,
+}
+
+#[async_trait]
+impl<H, O> Handler<Request> for CsrfFormField<H>
+where
+    H: Handler<Request, Output = Result<O>>,
+    O: IntoResponse,
+{
+    type Output = Result<Response>;
+
+    async fn call(&self, mut req: Request) -> Self::Output {
+        let is_form = req
+            .header::<_, String>(CONTENT_TYPE)
+            .is_some_and(|v| v.starts_with("application/x-www-form-urlencoded"));
+        if req.method() == Method::POST && is_form && !req.headers().contains_key(CSRF_HEADER) {
+            let body = req.bytes().await?;
+            let token = serde_urlencoded::from_bytes::<Vec<(String, String)>>(&body)
+                .ok()
+                .and_then(|fields| fields.into_iter().find(|(k, _)| k == CSRF_FORM_FIELD))
+                .and_then(|(_, v)| HeaderValue::try_from(v).ok());
+            if let Some(token) = token {
+                req.headers_mut()
+                    .insert(HeaderName::from_static(CSRF_HEADER), token);
+            }
+
+            // Put the body back for the handler to read.
+            *req.body_mut() = Body::from(Full::new(body));
+            req.extensions_mut().insert(BodyState::Normal);
+        }
+
+        self.h.call(req).await.map(IntoResponse::into_response)
+    }
+}
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+/// Who is behind a request without a browser session, e.g. one made with an API key, once a handler finds out.
+#[derive(Debug, Clone, Default)]
+struct RequestUser(Arc<OnceLock<u32>>);
+
+pub fn request_id(r: &Request) -> String {
+    r.extensions()
+        .get::<RequestId>()
+        .map(|id| id.0.clone())
+        .unwrap_or_default()
+}
+
+/// Attribute the request to a user in the access log.
+pub fn record_user(r: &Request, user_id: u32) {
+    if let Some(user) = r.extensions().get::<RequestUser>() {
+        let _ = user.0.set(user_id);
+    }
+}
+
+/// Ids from upstream proxies are kept, as long as they can't mess up the log.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+}
+
+/// Error responses carry the request id, so that users have something to quote when they report a problem.
+async fn add_request_id_to_body(resp: Response, id: &str) -> Response {
+    let is_text = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_none_or(|v| v.starts_with("text/plain"));
+    if !is_text {
+        return resp;
+    }
+
+    let (mut parts, body) = resp.into_parts();
+    let body = match body.collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(_) => Bytes::new(),
+    };
+    let text = match String::from_utf8_lossy(&body).trim_end() {
+        "" => parts.status.to_string(),
+        text => text.to_owned(),
+    };
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    Response::from_parts(
+        parts,
+        Body::from(Full::new(Bytes::from(format!(
+            "{}\nrequest id: {}\n",
+            text, id
+        )))),
+    )
+}
+
+/// Tags every request with an id, returned in `X-Request-Id`, and logs one line per request under the `relay::access` target.
+#[derive(Debug, Clone, Default)]
+pub struct AccessLogConfig;
+
+impl<H> Transform<H> for AccessLogConfig
+where
+    H: Clone,
+{
+    type Output = AccessLog<H>;
+
+    fn transform(&self, h: H) -> Self::Output {
+        AccessLog { h }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessLog<H> {
+    h: H,
+}
+
+#[async_trait]
+impl<H, O> Handler<Request> for AccessLog<H>
+where
+    H: Handler<Request, Output = Result<O>>,
+    O: IntoResponse,
+{
+    type Output = Result<Response>;
+
+    async fn call(&self, mut req: Request) -> Self::Output {
+        let started = Instant::now();
+        let id = req
+            .header::<_, String>(REQUEST_ID_HEADER)
+            .filter(|id| is_valid_request_id(id))
+            .unwrap_or_else(|| nanoid::nanoid!(16));
+        let route = req
+            .extensions()
+            .get::<Arc<RouteInfo>>()
+            .map(|info| info.pattern.clone())
+            .unwrap_or_default();
+        let method = req.method().clone();
+        let client_ip = client_ip(&req);
+        // The session outlives the request, so it can be asked who logged in after the handler is done.
+        let session = req.extensions().get::<Session>().cloned();
+        let user = RequestUser::default();
+        req.extensions_mut().insert(RequestId(id.clone()));
+        req.extensions_mut().insert(user.clone());
+
+        let mut resp = self
+            .h
+            .call(req)
+            .await
+            .map_or_else(IntoResponse::into_response, IntoResponse::into_response);
+        if resp.status().is_client_error() || resp.status().is_server_error() {
+            resp = add_request_id_to_body(resp, &id).await;
+        }
+        if let Ok(value) = HeaderValue::try_from(id.as_str()) {
+            resp.headers_mut()
+                .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+        }
+
+        let user_id =
+            user.0.get().copied().or_else(|| {
+                session.and_then(|s| s.get::<u32>(SESSION_FIELD_USER_ID).ok().flatten())
+            });
+        log::info!(
+            target: "relay::access",
+            "request_id={} method={} route={} status={} latency_ms={} client_ip={} user_id={}",
+            id,
+            method,
+            route,
+            resp.status().as_u16(),
+            started.elapsed().as_millis(),
+            client_ip.map_or_else(|| "-".to_owned(), |ip| ip.to_string()),
+            user_id.map_or_else(|| "-".to_owned(), |id| id.to_string()),
+        );
+        Ok(resp)
+    }
+}
+
+/// Who sent a request and how, as far as the trusted proxies in front of relay can tell.
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub ip: IpAddr,
+    /// `http` or `https`, as seen by the client -- known only if a trusted proxy said so.
+    pub scheme: Option<String>,
+}
+
+pub fn client_info(r: &Request) -> Option<ClientInfo> {
+    r.extensions().get::<ClientInfo>().cloned()
+}
+
+/// The address of the client, falling back to the other end of the connection.
+pub fn client_ip(r: &Request) -> Option<IpAddr> {
+    client_info(r)
+        .map(|info| info.ip)
+        .or_else(|| peer_addr(r).map(|addr| addr.ip()))
+}
+
+/// One proxy hop: whom the proxy got the request from, and over which protocol.
+#[derive(Debug, Default)]
+struct Hop {
+    ip: Option<IpAddr>,
+    proto: Option<String>,
+}
+
+/// Addresses may come quoted, with a port, or with IPv6 brackets. Obfuscated ones and `unknown` give `None`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    node.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+}
+
+/// RFC 7239, e.g. `for=192.0.2.60;proto=https, for="[2001:db8::17]:4711"`.
+fn parse_forwarded(values: &[&str]) -> Vec<Hop> {
+    values
+        .iter()
+        .flat_map(|v| v.split(','))
+        .map(|element| {
+            let mut hop = Hop::default();
+            for pair in element.split(';') {
+                if let Some((key, value)) = pair.split_once('=') {
+                    match key.trim().to_ascii_lowercase().as_str() {
+                        "for" => hop.ip = parse_node(value),
+                        "proto" => {
+                            hop.proto = Some(value.trim().trim_matches('"').to_ascii_lowercase())
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            hop
+        })
+        .collect()
+}
+
+fn parse_x_forwarded(for_values: &[&str], proto_values: &[&str]) -> Vec<Hop> {
+    let mut ips = for_values
+        .iter()
+        .flat_map(|v| v.split(','))
+        .map(parse_node)
+        .collect::<Vec<_>>();
+    let protos = proto_values
+        .iter()
+        .flat_map(|v| v.split(','))
+        .map(|p| p.trim().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    if ips.is_empty() && !protos.is_empty() {
+        // The proxy only reported the protocol, and the client is the proxy's peer.
+        ips.push(None);
+    }
+    // Proxies which only ever set one protocol speak for the whole chain.
+    let aligned = protos.len() == ips.len();
+    ips.into_iter()
+        .enumerate()
+        .map(|(i, ip)| Hop {
+            ip,
+            proto: if aligned {
+                protos.get(i).cloned()
+            } else {
+                protos.last().cloned()
+            },
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientInfoConfig {
+    trusted_proxies: Arc<Vec<IpNet>>,
+    header: ForwardedHeader,
+}
+
+impl ClientInfoConfig {
+    pub fn new(trusted_proxies: Vec<IpNet>, header: ForwardedHeader) -> Self {
+        Self {
+            trusted_proxies: Arc::new(trusted_proxies),
+            header,
+        }
+    }
+
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+
+    /// Walk the chain of proxies back from relay, up to the first hop which isn't trusted to tell the truth.
+    fn resolve(&self, headers: &HeaderMap, peer: IpAddr) -> ClientInfo {
+        let mut client = ClientInfo {
+            ip: peer,
+            scheme: None,
+        };
+        if !self.is_trusted(&peer) {
+            return client;
+        }
+
+        let values = |name: &str| {
+            headers
+                .get_all(name)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .collect::<Vec<_>>()
+        };
+        let hops = match self.header {
+            ForwardedHeader::Forwarded => parse_forwarded(&values("forwarded")),
+            ForwardedHeader::XForwardedFor => {
+                parse_x_forwarded(&values("x-forwarded-for"), &values("x-forwarded-proto"))
+            }
+        };
+
+        for hop in hops.into_iter().rev() {
+            if let Some(proto) = hop.proto.filter(|p| p == "http" || p == "https") {
+                client.scheme = Some(proto);
+            }
+            match hop.ip {
+                Some(ip) => {
+                    client.ip = ip;
+                    if !self.is_trusted(&ip) {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+impl<H> Transform<H> for ClientInfoConfig
+where
+    H: Clone,
+{
+    type Output = ClientInfoMiddleware<H>;
+
+    fn transform(&self, h: H) -> Self::Output {
+        ClientInfoMiddleware {
+            h,
+            config: self.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientInfoMiddleware<H> {
+    h: H,
+    config: ClientInfoConfig,
+}
+
+#[async_trait]
+impl<H, O> Handler<Request> for ClientInfoMiddleware<H>
+where
+    H: Handler<Request, Output = Result<O>>,
+    O: IntoResponse,
+{
+    type Output = Result<Response>;
+
+    async fn call(&self, mut req: Request) -> Self::Output {
+        if let Some(peer) = peer_addr(&req) {
+            let info = self.config.resolve(req.headers(), peer.ip());
+            req.extensions_mut().insert(info);
+        }
+        self.h.call(req).await.map(IntoResponse::into_response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn ips(hops: &[Hop]) -> Vec<Option<IpAddr>> {
+        hops.iter().map(|hop| hop.ip).collect()
+    }
+
+    fn protos(hops: &[Hop]) -> Vec<Option<&str>> {
+        hops.iter().map(|hop| hop.proto.as_deref()).collect()
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(*name, HeaderValue::from_str(value).unwrap());
+        }
+        map
+    }
+
+    fn proxies(header: ForwardedHeader) -> ClientInfoConfig {
+        ClientInfoConfig::new(
+            vec!["10.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()],
+            header,
+        )
+    }
+
+    #[test]
+    fn parse_node_formats() {
+        assert_eq!(parse_node("192.0.2.60"), Some(ip("192.0.2.60")));
+        assert_eq!(parse_node(" 192.0.2.60:4711 "), Some(ip("192.0.2.60")));
+        assert_eq!(parse_node("2001:db8::17"), Some(ip("2001:db8::17")));
+        assert_eq!(
+            parse_node("\"[2001:db8::17]:4711\""),
+            Some(ip("2001:db8::17"))
+        );
+        assert_eq!(parse_node("[2001:db8::17]"), Some(ip("2001:db8::17")));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+        assert_eq!(parse_node("[not-an-ip]:80"), None);
+        assert_eq!(parse_node(""), None);
+    }
+
+    #[test]
+    fn parse_forwarded_elements() {
+        let hops = parse_forwarded(&[
+            "for=192.0.2.60;proto=HTTP;by=203.0.113.43, for=\"[2001:db8::17]:4711\"",
+            "For=10.1.2.3",
+        ]);
+        assert_eq!(
+            ips(&hops),
+            [
+                Some(ip("192.0.2.60")),
+                Some(ip("2001:db8::17")),
+                Some(ip("10.1.2.3"))
+            ]
+        );
+        assert_eq!(protos(&hops), [Some("http"), None, None]);
+    }
+
+    #[test]
+    fn parse_forwarded_malformed() {
+        let hops = parse_forwarded(&["garbage, for=unknown, for=_hidden;proto=https, =;;="]);
+        assert_eq!(ips(&hops), [None, None, None, None]);
+        assert_eq!(protos(&hops), [None, None, Some("https"), None]);
+    }
+
+    #[test]
+    fn parse_x_forwarded_lists() {
+        let hops = parse_x_forwarded(
+            &["203.0.113.7, [2001:db8::17]:4711", "10.1.2.3:8080"],
+            &["https, http", "http"],
+        );
+        assert_eq!(
+            ips(&hops),
+            [
+                Some(ip("203.0.113.7")),
+                Some(ip("2001:db8::17")),
+                Some(ip("10.1.2.3"))
+            ]
+        );
+        assert_eq!(protos(&hops), [Some("https"), Some("http"), Some("http")]);
+    }
+
+    #[test]
+    fn parse_x_forwarded_single_proto() {
+        let hops = parse_x_forwarded(&["203.0.113.7, 10.1.2.3"], &["HTTPS"]);
+        assert_eq!(protos(&hops), [Some("https"), Some("https")]);
+
+        let hops = parse_x_forwarded(&[], &["https"]);
+        assert_eq!(ips(&hops), [None]);
+        assert_eq!(protos(&hops), [Some("https")]);
+
+        let hops = parse_x_forwarded(&["not an address"], &[]);
+        assert_eq!(ips(&hops), [None]);
+        assert!(parse_x_forwarded(&[], &[]).is_empty());
+    }
+
+    #[test]
+    fn resolve_ignores_untrusted_peers() {
+        let spoofed = headers(&[
+            ("x-forwarded-for", "198.51.100.1"),
+            ("x-forwarded-proto", "http"),
+        ]);
+        let client = proxies(ForwardedHeader::XForwardedFor).resolve(&spoofed, ip("203.0.113.7"));
+        assert_eq!(client.ip, ip("203.0.113.7"));
+        assert_eq!(client.scheme, None);
+    }
+
+    #[test]
+    fn resolve_stops_at_first_untrusted_hop() {
+        // The client made up the first entry, the proxies appended the rest.
+        let chain = headers(&[
+            ("x-forwarded-for", "198.51.100.1, 203.0.113.7, 10.0.0.2"),
+            ("x-forwarded-proto", "https"),
+        ]);
+        let client = proxies(ForwardedHeader::XForwardedFor).resolve(&chain, ip("10.0.0.1"));
+        assert_eq!(client.ip, ip("203.0.113.7"));
+        assert_eq!(client.scheme.as_deref(), Some("https"));
+    }
+
+    #[test]
+    fn resolve_over_ipv6() {
+        let chain = headers(&[("forwarded", "for=\"[2001:db8::17]:4711\";proto=http")]);
+        let client = proxies(ForwardedHeader::Forwarded).resolve(&chain, ip("::1"));
+        assert_eq!(client.ip, ip("2001:db8::17"));
+        assert_eq!(client.scheme.as_deref(), Some("http"));
+    }
+
+    #[test]
+    fn resolve_keeps_the_proxy_on_malformed_hops() {
+        let chain = headers(&[
+            ("x-forwarded-for", "unknown"),
+            ("x-forwarded-proto", "gopher"),
+        ]);
+        let client = proxies(ForwardedHeader::XForwardedFor).resolve(&chain, ip("10.0.0.1"));
+        assert_eq!(client.ip, ip("10.0.0.1"));
+        assert_eq!(client.scheme, None);
+    }
+
+    #[test]
+    fn resolve_only_reads_the_configured_header() {
+        let both = headers(&[
+            ("forwarded", "for=198.51.100.1;proto=http"),
+            ("x-forwarded-for", "203.0.113.7"),
+            ("x-forwarded-proto", "https"),
+        ]);
+
+        let client = proxies(ForwardedHeader::XForwardedFor).resolve(&both, ip("10.0.0.1"));
+        assert_eq!(client.ip, ip("203.0.113.7"));
+        assert_eq!(client.scheme.as_deref(), Some("https"));
+
+        let client = proxies(ForwardedHeader::Forwarded).resolve(&both, ip("10.0.0.1"));
+        assert_eq!(client.ip, ip("198.51.100.1"));
+        assert_eq!(client.scheme.as_deref(), Some("http"));
     }
 }
@@ -1,7 +1,12 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+use crate::secrets::redact;
+
+#[derive(Clone, Deserialize, Serialize)]
 pub struct AccessToken {
     pub access_token: String,
     pub expires_in: i32,
@@ -12,6 +17,18 @@ pub struct AccessToken {
     pub ctime: i64,
 }
 
+impl fmt::Debug for AccessToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccessToken")
+            .field("access_token", &redact(&self.access_token))
+            .field("expires_in", &self.expires_in)
+            .field("refresh_token", &redact(&self.refresh_token))
+            .field("token_type", &self.token_type)
+            .field("ctime", &self.ctime)
+            .finish()
+    }
+}
+
 impl AccessToken {
     pub fn obtained_at(&self) -> DateTime<Utc> {
         DateTime::from_timestamp(self.ctime, 0).unwrap()
@@ -30,6 +47,34 @@ impl AccessToken {
     }
 }
 
+/// A token obtained through the client credentials grant, which comes without a refresh token.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct AppToken {
+    pub access_token: String,
+    pub expires_in: i32,
+    pub token_type: String,
+
+    #[serde(default = "utcnow")]
+    pub ctime: i64,
+}
+
+impl fmt::Debug for AppToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AppToken")
+            .field("access_token", &redact(&self.access_token))
+            .field("expires_in", &self.expires_in)
+            .field("token_type", &self.token_type)
+            .field("ctime", &self.ctime)
+            .finish()
+    }
+}
+
+impl AppToken {
+    pub fn lifetime(&self) -> i64 {
+        0.max(self.ctime + self.expires_in as i64 - Utc::now().timestamp())
+    }
+}
+
 fn utcnow() -> i64 {
     Utc::now().timestamp()
 }
@@ -40,10 +85,273 @@ pub struct OAuth2FeedbackQuery {
     pub state: String,
 }
 
+/// An error reported by osu! web, either in the query of the callback or in the body of a failed request.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OAuth2Error {
+    pub error: String,
+    pub error_description: Option<String>,
+    pub hint: Option<String>,
+    pub state: Option<String>,
+}
+
+impl OAuth2Error {
+    pub fn describe(&self) -> String {
+        let description = self.error_description.as_deref().unwrap_or(&self.error);
+        match &self.hint {
+            Some(hint) => format!("{} ({})", description, hint),
+            None => description.to_owned(),
+        }
+    }
+}
+
+/// A login started on this relay, waiting for the user to come back from osu! web.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LoginAttempt {
+    pub state: String,
+    pub code_verifier: String,
+    pub app: String,
+    pub scope: Vec<String>,
+    pub ctime: i64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AppQuery {
+    pub app: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ScopeQuery {
+    pub scope: String,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct UserCompact {
     #[serde(rename = "id")]
     pub user_id: u32,
     pub username: String,
     pub avatar_url: String,
+
+    #[serde(default)]
+    pub country_code: Option<String>,
+    #[serde(default)]
+    pub country: Option<Country>,
+    #[serde(default)]
+    pub cover: Option<UserCover>,
+    #[serde(default)]
+    pub is_supporter: Option<bool>,
+    #[serde(default)]
+    pub groups: Option<Vec<UserGroup>>,
+
+    /// When the profile was fetched from osu!.
+    #[serde(default = "utcnow")]
+    pub ctime: i64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Country {
+    pub code: String,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UserGroup {
+    pub identifier: String,
+    pub name: String,
+    pub short_name: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UserCover {
+    pub url: Option<String>,
+    pub custom_url: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Pairing {
+    pub user_code: String,
+    pub session_id: Option<String>,
+
+    #[serde(default)]
+    pub scope: Option<Vec<String>>,
+    #[serde(default)]
+    pub app: Option<String>,
+
+    #[serde(default = "utcnow")]
+    pub ctime: i64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PairingStartRequest {
+    pub scope: Option<Vec<String>>,
+    pub app: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PairingChallenge {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_url: String,
+    pub verification_url_complete: String,
+    pub expires_in: u64,
+    pub interval: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PairingPollRequest {
+    pub device_code: String,
+    pub label: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum PairingStatus {
+    Pending,
+    Complete { api_key: NewApiKey },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PairingForm {
+    pub user_code: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HandoffQuery {
+    pub return_to: String,
+    pub nonce: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ExchangeCode {
+    pub session_id: String,
+    pub nonce: String,
+
+    #[serde(default = "utcnow")]
+    pub ctime: i64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ExchangeRequest {
+    pub code: String,
+    pub nonce: String,
+    pub label: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub label: String,
+    pub hash: String,
+
+    #[serde(default = "utcnow")]
+    pub ctime: i64,
+    pub last_used: Option<i64>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ApiKeyOwner {
+    pub session_id: String,
+    pub key_id: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ApiKeyInfo {
+    pub id: String,
+    pub label: String,
+    pub ctime: i64,
+    pub last_used: Option<i64>,
+}
+
+impl From<ApiKey> for ApiKeyInfo {
+    fn from(key: ApiKey) -> Self {
+        Self {
+            id: key.id,
+            label: key.label,
+            ctime: key.ctime,
+            last_used: key.last_used,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NewApiKey {
+    pub id: String,
+    pub label: String,
+    pub key: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ApiKeyForm {
+    pub label: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ApiKeyRevocationForm {
+    pub id: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DeviceInfo {
+    pub id: String,
+    pub user_agent: Option<String>,
+
+    #[serde(default = "utcnow")]
+    pub ctime: i64,
+    #[serde(default = "utcnow")]
+    pub last_seen: i64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Device {
+    pub id: String,
+    pub current: bool,
+    pub user_agent: Option<String>,
+    pub clients: Vec<String>,
+    pub ctime: i64,
+    pub last_used: i64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DeviceRevocationForm {
+    pub id: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HealthCheck {
+    pub ok: bool,
+    pub detail: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HealthReport {
+    pub ok: bool,
+    pub checks: BTreeMap<String, HealthCheck>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RefreshFailure {
+    /// Fingerprint of the session id. Failures recorded before these were kept have none.
+    #[serde(default)]
+    pub session: String,
+    pub error: String,
+    pub ctime: i64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SessionSummary {
+    /// Fingerprint of the session id, which stands for the session on the admin pages.
+    pub fingerprint: String,
+    pub user: Option<UserCompact>,
+    pub app: Option<String>,
+    /// Seconds until the session itself expires.
+    pub ttl: Option<i64>,
+    pub token_expires_in: Option<i64>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AdminOverview {
+    pub total_sessions: usize,
+    pub expiring_soon: usize,
+    /// The sessions whose tokens expire first, up to a limit.
+    pub expiring: Vec<SessionSummary>,
+    pub failures: Vec<RefreshFailure>,
 }
@@ -0,0 +1,144 @@
+use chrono::Utc;
+use nanoid::nanoid;
+
+use crate::api_keys;
+use crate::config::App;
+use crate::model::{Pairing, PairingStatus};
+use crate::storage::{ValkeyStorage, PAIRING_CODE_KEY_PREFIX, PAIRING_KEY_PREFIX};
+
+pub const PAIRING_LIFETIME_SECS: u64 = 10 * 60;
+pub const PAIRING_POLL_INTERVAL_SECS: u64 = 5;
+pub const PAIRING_PAGE_PATH: &str = "/auth/pair";
+
+// No vowels and no lookalikes, so that the code is easy to read off one screen and type into another.
+const USER_CODE_ALPHABET: [char; 20] = [
+    'B', 'C', 'D', 'F', 'G', 'H', 'J', 'K', 'L', 'M', 'N', 'P', 'Q', 'R', 'S', 'T', 'V', 'W', 'X',
+    'Z',
+];
+const USER_CODE_LENGTH: usize = 8;
+const DEVICE_CODE_LENGTH: usize = 32;
+
+fn pairing_key(device_code: &str) -> String {
+    format!("{}{}", PAIRING_KEY_PREFIX, device_code)
+}
+
+fn user_code_key(user_code: &str) -> String {
+    format!(
+        "{}{}",
+        PAIRING_CODE_KEY_PREFIX,
+        normalize_user_code(user_code)
+    )
+}
+
+pub fn normalize_user_code(raw: &str) -> String {
+    raw.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+pub fn format_user_code(user_code: &str) -> String {
+    let (head, tail) = user_code.split_at(user_code.len() / 2);
+    format!("{}-{}", head, tail)
+}
+
+/// The page where users type in the code, living next to the OAuth redirect target.
+pub fn verification_url(app: &App) -> eyre::Result<reqwest::Url> {
+    Ok(reqwest::Url::parse(&app.redirect_url)?.join(PAIRING_PAGE_PATH)?)
+}
+
+/// Create a pending pairing, returning the device code to be polled by the client along with it.
+pub async fn start(
+    storage: &ValkeyStorage,
+    scope: Option<Vec<String>>,
+    app: Option<String>,
+) -> eyre::Result<(String, Pairing)> {
+    let device_code = nanoid!(DEVICE_CODE_LENGTH);
+    let pairing = Pairing {
+        user_code: nanoid!(USER_CODE_LENGTH, &USER_CODE_ALPHABET),
+        session_id: None,
+        scope,
+        app,
+        ctime: Utc::now().timestamp(),
+    };
+
+    storage
+        .set_json(
+            &pairing_key(&device_code),
+            &pairing,
+            Some(PAIRING_LIFETIME_SECS),
+        )
+        .await?;
+    storage
+        .set_json(
+            &user_code_key(&pairing.user_code),
+            &device_code,
+            Some(PAIRING_LIFETIME_SECS),
+        )
+        .await?;
+
+    Ok((device_code, pairing))
+}
+
+/// Look up a pending pairing by the code the user has typed in, returning it along with its device code.
+pub async fn find(
+    storage: &ValkeyStorage,
+    user_code: &str,
+) -> eyre::Result<Option<(String, Pairing)>> {
+    let device_code = match storage
+        .get_json::<String>(&user_code_key(user_code))
+        .await?
+    {
+        Some(device_code) => device_code,
+        None => return Ok(None),
+    };
+    Ok(storage
+        .get_json::<Pairing>(&pairing_key(&device_code))
+        .await?
+        .map(|pairing| (device_code, pairing)))
+}
+
+/// Attach a session to a pending pairing. Returns `false` if the pairing has expired or was already used.
+pub async fn complete(
+    storage: &ValkeyStorage,
+    device_code: &str,
+    session_id: &str,
+) -> eyre::Result<bool> {
+    let key = pairing_key(device_code);
+    match storage.get_json::<Pairing>(&key).await? {
+        Some(mut pairing) if pairing.session_id.is_none() => {
+            pairing.session_id = Some(session_id.to_owned());
+            storage.set_json(&key, &pairing, None).await?;
+            storage
+                .delete(&[user_code_key(&pairing.user_code).as_str()])
+                .await?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Check on a pairing. A completed pairing yields an API key exactly once and is forgotten afterwards.
+pub async fn poll(
+    storage: &ValkeyStorage,
+    device_code: &str,
+    label: Option<&str>,
+) -> eyre::Result<Option<PairingStatus>> {
+    let key = pairing_key(device_code);
+    match storage.get_json::<Pairing>(&key).await? {
+        None => Ok(None),
+        Some(Pairing {
+            session_id: None, ..
+        }) => Ok(Some(PairingStatus::Pending)),
+        Some(_) => match storage
+            .take_json::<Pairing>(&key)
+            .await?
+            .and_then(|pairing| pairing.session_id)
+        {
+            Some(session_id) => Ok(Some(PairingStatus::Complete {
+                api_key: api_keys::create(storage, &session_id, label).await?,
+            })),
+            None => Ok(None),
+        },
+    }
+}
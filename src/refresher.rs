@@ -1,35 +1,88 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::HashMap;
 use std::iter::zip;
+use std::sync::atomic::{AtomicI64, Ordering};
 use std::sync::Arc;
 
+use chrono::{DateTime, Utc};
 use rand::Rng;
-use redis::aio::MultiplexedConnection;
 use redis::AsyncCommands;
 use sessions::Storage;
 use tokio::time::sleep;
 
-use crate::config::Config;
-use crate::handlers::auth::{API_AUTHENTICATION_URL, SESSION_FIELD_TOKEN};
-use crate::model::AccessToken;
-use crate::storage::ValkeyStorage;
+use crate::access;
+use crate::app_tokens;
+use crate::config::{self, App, Config};
+use crate::handlers::auth::{
+    make_user_data_request, SESSION_FIELD_APP, SESSION_FIELD_TOKEN, SESSION_FIELD_USER,
+};
+use crate::metrics;
+use crate::model::{AccessToken, OAuth2Error, RefreshFailure, UserCompact};
+use crate::secrets::{self, redact};
+use crate::storage::{self, ValkeyStorage, REFRESH_FAILURES_KEY, TOKEN_EXPIRY_KEY};
 
 const SHORT_SLEEP_SECS: u64 = 30;
 const LONG_SLEEP_SECS: u64 = 60 * 60;
 const AVG_UPDATES_PER_MINUTE: u64 = 50;
-const UPDATE_THRESHOLD_SECS: i32 = 4 * 60 * 60;
+/// Sessions whose tokens expire sooner than this are refreshed on the next sweep.
+pub const UPDATE_THRESHOLD_SECS: i32 = 4 * 60 * 60;
+const MAX_RECORDED_FAILURES: isize = 50;
+
+/// How long the refresher may go without finishing a sweep before it is considered stuck.
+pub const HEARTBEAT_TIMEOUT_SECS: i64 = 2 * LONG_SLEEP_SECS as i64;
+
+/// Liveness of the refresher loop, shared with the readiness check.
+#[derive(Debug, Clone)]
+pub struct RefresherStatus {
+    started_at: i64,
+    last_sweep: Arc<AtomicI64>,
+}
+
+impl Default for RefresherStatus {
+    fn default() -> Self {
+        Self {
+            started_at: Utc::now().timestamp(),
+            last_sweep: Arc::default(),
+        }
+    }
+}
+
+impl RefresherStatus {
+    fn record_sweep(&self) {
+        self.last_sweep
+            .store(Utc::now().timestamp(), Ordering::Release);
+    }
+
+    pub fn last_sweep(&self) -> Option<DateTime<Utc>> {
+        match self.last_sweep.load(Ordering::Acquire) {
+            0 => None,
+            ts => DateTime::from_timestamp(ts, 0),
+        }
+    }
+
+    /// The refresher is healthy if it has swept recently, or hasn't been running long enough to be expected to.
+    pub fn is_alive(&self) -> bool {
+        let since = match self.last_sweep.load(Ordering::Acquire) {
+            0 => self.started_at,
+            ts => ts,
+        };
+        Utc::now().timestamp() - since <= HEARTBEAT_TIMEOUT_SECS
+    }
+}
 
 pub struct TokenRefresher {
     config: Config,
     storage: ValkeyStorage,
+    status: RefresherStatus,
 
     task: Option<tokio::task::JoinHandle<()>>,
 }
 
 impl TokenRefresher {
-    pub fn new(config: Config, storage: ValkeyStorage) -> Self {
+    pub fn new(config: Config, storage: ValkeyStorage, status: RefresherStatus) -> Self {
         Self {
             config,
             storage,
+            status,
             task: None,
         }
     }
@@ -38,7 +91,8 @@ impl TokenRefresher {
         if self.task.is_none() {
             let config = self.config.clone();
             let storage = self.storage.clone();
-            self.task = Some(tokio::spawn(refresher_loop(config, storage)));
+            let status = self.status.clone();
+            self.task = Some(tokio::spawn(refresher_loop(config, storage, status)));
         }
     }
 
@@ -49,9 +103,10 @@ impl TokenRefresher {
     }
 }
 
-async fn refresher_loop(config: Config, storage: ValkeyStorage) {
+async fn refresher_loop(config: Config, storage: ValkeyStorage, status: RefresherStatus) {
     let config = Arc::new(config);
     let storage = Arc::new(storage);
+    let mut indexed = false;
 
     loop {
         match storage.client.get_multiplexed_tokio_connection().await {
@@ -61,12 +116,44 @@ async fn refresher_loop(config: Config, storage: ValkeyStorage) {
             }
             Ok(mut conn) => {
                 let now = std::time::Instant::now();
-                match conn.keys::<&str, Vec<String>>("*").await {
+                let keys_timer = metrics::VALKEY_COMMAND_DURATION
+                    .with_label_values(&["keys"])
+                    .start_timer();
+                let keys = conn.keys::<&str, Vec<String>>("*").await;
+                keys_timer.observe_duration();
+                match keys {
                     Err(e) => {
                         log::error!("Failed to read all sessions from Valkey: {}", e);
                         sleep(std::time::Duration::from_secs(SHORT_SLEEP_SECS)).await;
                     }
-                    Ok(all_sessions) => {
+                    Ok(mut all_sessions) => {
+                        all_sessions.retain(|k| storage::is_session_key(k));
+                        let sweep_timer = metrics::REFRESHER_SWEEP_DURATION.start_timer();
+                        match access::purge(&config.access, &storage).await {
+                            Ok(0) => {}
+                            Ok(purged) => log::info!(
+                                "Purged {} session(s) of users who may no longer log in",
+                                purged
+                            ),
+                            Err(e) => {
+                                log::error!("Failed to purge sessions of denied users: {}", e)
+                            }
+                        }
+                        let app_token_failures = app_tokens::refresh_expiring(
+                            &config,
+                            &storage,
+                            UPDATE_THRESHOLD_SECS as i64,
+                        )
+                        .await;
+                        if app_token_failures > 0 {
+                            log::warn!("{} app token(s) couldn't be renewed", app_token_failures);
+                            metrics::REFRESHER_SESSIONS
+                                .with_label_values(&["failed"])
+                                .inc_by(app_token_failures);
+                        }
+                        metrics::REFRESHER_SESSIONS
+                            .with_label_values(&["scanned"])
+                            .inc_by(all_sessions.len() as u64);
                         log::info!(
                             "{} session(s) total ({}ms)",
                             all_sessions.len(),
@@ -74,22 +161,27 @@ async fn refresher_loop(config: Config, storage: ValkeyStorage) {
                         );
 
                         let now = std::time::Instant::now();
-                        let mut ttl_tasks = Vec::with_capacity(all_sessions.len());
-                        for k in all_sessions.iter().cloned() {
-                            ttl_tasks.push(tokio::spawn(fetch_ttl(conn.clone(), k)));
-                        }
-                        let mut scheduled_for_update = Vec::with_capacity(all_sessions.len());
-                        for (key, handle) in zip(all_sessions.into_iter(), ttl_tasks) {
-                            if let Ok(Some(exp)) = handle.await {
-                                if exp <= UPDATE_THRESHOLD_SECS {
-                                    scheduled_for_update.push(key);
-                                }
+                        if !indexed {
+                            match index_existing_sessions(&storage, &all_sessions).await {
+                                Ok(()) => indexed = true,
+                                Err(e) => log::error!(
+                                    "Failed to index the token expiry of existing sessions: {}",
+                                    e
+                                ),
                             }
                         }
+                        let deadline = Utc::now().timestamp() + UPDATE_THRESHOLD_SECS as i64;
+                        let scheduled_for_update = match sessions_due(&storage, deadline).await {
+                            Ok(keys) => keys,
+                            Err(e) => {
+                                log::error!("Failed to find sessions due for a refresh: {}", e);
+                                Vec::new()
+                            }
+                        };
 
                         let keys_len = scheduled_for_update.len();
                         log::info!(
-                            "{} session(s) with ttl less than {}s ({}ms)",
+                            "{} session(s) with tokens expiring within {}s ({}ms)",
                             keys_len,
                             UPDATE_THRESHOLD_SECS,
                             now.elapsed().as_millis()
@@ -104,7 +196,6 @@ async fn refresher_loop(config: Config, storage: ValkeyStorage) {
                             let task = tokio::spawn(refresh_single_token(
                                 config.clone(),
                                 storage.clone(),
-                                conn.clone(),
                                 k,
                                 keys_len as u64,
                             ));
@@ -113,7 +204,11 @@ async fn refresher_loop(config: Config, storage: ValkeyStorage) {
 
                         for handle in tasks {
                             match handle.await {
-                                Ok(_) => successes += 1,
+                                Ok(Ok(())) => successes += 1,
+                                Ok(Err(e)) => {
+                                    log::warn!("Failed to update one of tokens: {}", e);
+                                    failures += 1
+                                }
                                 Err(e) => {
                                     log::warn!(
                                         "Failed to update one of tokens due to unhandled error: {}",
@@ -124,6 +219,14 @@ async fn refresher_loop(config: Config, storage: ValkeyStorage) {
                             }
                         }
 
+                        sweep_timer.observe_duration();
+                        metrics::REFRESHER_SESSIONS
+                            .with_label_values(&["refreshed"])
+                            .inc_by(successes);
+                        metrics::REFRESHER_SESSIONS
+                            .with_label_values(&["failed"])
+                            .inc_by(failures);
+                        status.record_sweep();
                         log::info!(
                             "Success: {}, failure: {} ({}ms) -- sleeping {}s",
                             successes,
@@ -140,19 +243,61 @@ async fn refresher_loop(config: Config, storage: ValkeyStorage) {
     }
 }
 
-async fn fetch_ttl(mut conn: MultiplexedConnection, k: String) -> Option<i32> {
-    match conn.ttl::<String, i32>(k).await {
-        Ok(val) => Some(val),
-        Err(_) => None,
-    }
+fn session_token(data: &sessions::Data) -> Option<AccessToken> {
+    data.get(SESSION_FIELD_TOKEN)
+        .and_then(|v| serde_json::from_value::<AccessToken>(v.clone()).ok())
+}
+
+/// Seconds until the osu! token of a session expires, or `None` if it has none.
+pub fn token_lifetime(data: &sessions::Data) -> Option<i64> {
+    session_token(data).map(|token| token.lifetime())
+}
+
+fn token_expiry(token: &AccessToken) -> i64 {
+    token.ctime + token.expires_in as i64
+}
+
+/// Have the session refreshed before its new token expires.
+pub async fn schedule(
+    storage: &ValkeyStorage,
+    session_id: &str,
+    token: &AccessToken,
+) -> eyre::Result<()> {
+    storage
+        .zadd(TOKEN_EXPIRY_KEY, session_id, token_expiry(token))
+        .await
+}
+
+/// Sessions from before the token expiry index existed aren't in it yet.
+async fn index_existing_sessions(storage: &ValkeyStorage, keys: &[String]) -> eyre::Result<()> {
+    let sessions = storage.mget_json::<sessions::Data>(keys).await?;
+    let members = zip(keys, sessions)
+        .filter_map(|(key, data)| Some((token_expiry(&session_token(&data?)?), key.clone())))
+        .collect::<Vec<_>>();
+    storage.zadd_new(TOKEN_EXPIRY_KEY, &members).await
 }
 
-fn make_token_refresh_request(config: &Config, refresh_token: &str) -> reqwest::Request {
+/// Sessions whose tokens expire before the deadline. Sessions which have expired themselves are dropped from the index.
+async fn sessions_due(storage: &ValkeyStorage, deadline: i64) -> eyre::Result<Vec<String>> {
+    let candidates = storage.zrange_up_to(TOKEN_EXPIRY_KEY, deadline).await?;
+    let sessions = storage.mget_json::<sessions::Data>(&candidates).await?;
+    let (due, gone): (Vec<_>, Vec<_>) =
+        zip(candidates, sessions).partition(|(_, data)| data.is_some());
+    let gone = gone.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+    storage.zrem(TOKEN_EXPIRY_KEY, &gone).await?;
+    Ok(due.into_iter().map(|(key, _)| key).collect())
+}
+
+fn make_token_refresh_request(
+    api: &config::API,
+    app: &App,
+    refresh_token: &str,
+) -> reqwest::Request {
     reqwest::Client::new()
-        .post(API_AUTHENTICATION_URL)
+        .post(api.token_url())
         .form(&HashMap::from([
-            ("client_id", config.api.client_id.to_string()),
-            ("client_secret", config.api.client_secret.clone()),
+            ("client_id", app.client_id.to_string()),
+            ("client_secret", app.client_secret.expose().to_owned()),
             ("grant_type", "refresh_token".to_owned()),
             ("refresh_token", refresh_token.to_owned()),
         ]))
@@ -161,10 +306,37 @@ fn make_token_refresh_request(config: &Config, refresh_token: &str) -> reqwest::
         .unwrap()
 }
 
+async fn fetch_user_data(config: &Config, token: &AccessToken) -> eyre::Result<UserCompact> {
+    let result = reqwest::Client::new()
+        .execute(make_user_data_request(&config.api, token))
+        .await;
+    metrics::observe_osu_api_response("me", &result);
+    let text = result?.error_for_status()?.text().await?;
+    Ok(serde_json::from_str(&text)?)
+}
+
+async fn record_failure(storage: &ValkeyStorage, key: &str, error: &eyre::Report) {
+    let failure = RefreshFailure {
+        session: secrets::fingerprint(key),
+        error: error.to_string(),
+        ctime: Utc::now().timestamp(),
+    };
+    if let Err(e) = storage
+        .push_json(REFRESH_FAILURES_KEY, &failure, MAX_RECORDED_FAILURES)
+        .await
+    {
+        log::error!("Failed to record a refresh failure in Valkey: {}", e);
+    }
+}
+
+/// The latest failed refreshes, newest first.
+pub async fn recent_failures(storage: &ValkeyStorage) -> eyre::Result<Vec<RefreshFailure>> {
+    storage.range_json(REFRESH_FAILURES_KEY).await
+}
+
 async fn refresh_single_token(
     config: Arc<Config>,
     storage: Arc<ValkeyStorage>,
-    mut conn: MultiplexedConnection,
     key: String,
     total_keys: u64,
 ) -> eyre::Result<()> {
@@ -176,38 +348,107 @@ async fn refresh_single_token(
         sleep(sleep_duration).await;
     }
 
-    let session_data = conn.get::<&str, String>(&key).await?;
-    let mut deserialized: BTreeMap<String, serde_json::Value> =
-        serde_json::from_str(&session_data)?;
+    let result = refresh_session(&config, &storage, &key).await;
+    if let Err(e) = &result {
+        record_failure(&storage, &key, e).await;
+    }
+    result
+}
+
+/// Refresh the token of a single session right away.
+pub async fn refresh_session(
+    config: &Config,
+    storage: &ValkeyStorage,
+    key: &str,
+) -> eyre::Result<()> {
+    let mut deserialized = match storage.get_json::<sessions::Data>(key).await? {
+        Some(data) => data,
+        None => {
+            storage.zrem(TOKEN_EXPIRY_KEY, &[key.to_owned()]).await?;
+            eyre::bail!("session {} doesn't exist", redact(key))
+        }
+    };
 
     if let Some(token) = deserialized.get(SESSION_FIELD_TOKEN) {
         let token: AccessToken = serde_json::from_value(token.clone())?;
 
-        let request = make_token_refresh_request(&config, &token.refresh_token);
-        let result = reqwest::Client::new().execute(request).await;
-
-        match result {
-            Err(e) => {
+        // Sessions from before apps were tracked belong to the default one.
+        let app_name = deserialized.get(SESSION_FIELD_APP).and_then(|v| v.as_str());
+        let app = match config.api.app(app_name) {
+            Some(app) => app,
+            None => {
                 log::warn!(
-                    "Failed to refresh osu! API token for {}: {}. Removing the whole session",
-                    key,
-                    e
+                    "Session {} was issued by osu! API app {:?}, which is no longer configured",
+                    redact(key),
+                    app_name
                 );
-                if let Err(e) = (*storage).remove(&key).await {
+                storage.zrem(TOKEN_EXPIRY_KEY, &[key.to_owned()]).await?;
+                return Ok(());
+            }
+        };
+
+        let request = make_token_refresh_request(&config.api, app, &token.refresh_token);
+        let result = reqwest::Client::new().execute(request).await;
+        metrics::observe_osu_api_response("token_refresh", &result);
+
+        // The session stays until its own lifetime ends, so that the refresh can be retried on the next sweep.
+        let response = match result {
+            Err(e) => eyre::bail!(
+                "failed to reach osu! web to refresh the token of {}: {}",
+                redact(key),
+                e
+            ),
+            Ok(response) => response,
+        };
+        let status = response.status();
+        if !status.is_success() {
+            let error = response
+                .text()
+                .await
+                .ok()
+                .and_then(|text| serde_json::from_str::<OAuth2Error>(&text).ok());
+            // Only a dead refresh token is the session's fault -- `invalid_client` and the like mean the app itself is
+            // misconfigured, and every session of it would be lost otherwise.
+            if error.as_ref().is_some_and(|e| e.error == "invalid_grant") {
+                if let Err(e) = storage.remove(key).await {
                     log::error!("Failed to remove the session from storage: {}", e);
                 }
+                eyre::bail!(
+                    "osu! web rejected the refresh token of {} ({}). Removed the whole session",
+                    redact(key),
+                    status
+                );
             }
-            Ok(result) => {
-                let text = result.text().await?;
-                let token: AccessToken = serde_json::from_str(&text)?;
-                let exp = std::time::Duration::from_secs(token.expires_in.try_into().unwrap());
-
-                deserialized.insert(SESSION_FIELD_TOKEN.to_owned(), serde_json::to_value(token)?);
-                if let Err(e) = (*storage).set(&key, deserialized, &exp).await {
-                    log::error!("Failed to insert {} with the updated token: {}", key, e);
-                }
+            eyre::bail!(
+                "osu! web responded with {} to refreshing the token of {}{}",
+                status,
+                redact(key),
+                error
+                    .map(|e| format!(": {}", e.describe()))
+                    .unwrap_or_default()
+            );
+        }
+
+        let text = response.text().await?;
+        let token: AccessToken = serde_json::from_str(&text)?;
+
+        match fetch_user_data(config, &token).await {
+            Ok(user) => {
+                deserialized.insert(SESSION_FIELD_USER.to_owned(), serde_json::to_value(user)?);
             }
+            Err(e) => log::warn!(
+                "Failed to update the profile cached in {}: {}",
+                redact(key),
+                e
+            ),
         }
+        deserialized.insert(
+            SESSION_FIELD_TOKEN.to_owned(),
+            serde_json::to_value(&token)?,
+        );
+        // Refreshing doesn't count as using the session, so its lifetime stays as it is.
+        storage.update_session(key, &deserialized).await?;
+        schedule(storage, key, &token).await?;
     }
 
     Ok(())
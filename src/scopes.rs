@@ -0,0 +1,58 @@
+use crate::config::Config;
+
+/// Needed to find out who the user is, so it's requested no matter what.
+pub const REQUIRED_SCOPE: &str = "identify";
+
+/// Split a scope list the way it arrives in a query string (`public chat.write`, `public,chat.write`).
+pub fn parse(raw: &str) -> Vec<String> {
+    raw.split([' ', ',', '+'])
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_owned())
+        .collect()
+}
+
+fn normalize(scopes: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut scopes: Vec<String> = scopes.into_iter().collect();
+    scopes.push(REQUIRED_SCOPE.to_owned());
+    scopes.sort();
+    scopes.dedup();
+    scopes
+}
+
+/// Check the requested scopes against the ones allowed in config.
+pub fn validate(config: &Config, requested: Vec<String>) -> Result<Vec<String>, String> {
+    let disallowed: Vec<&str> = requested
+        .iter()
+        .filter(|s| {
+            s.as_str() != REQUIRED_SCOPE
+                && !config.api.scope.contains(s)
+                && !config.api.allowed_scopes.contains(s)
+        })
+        .map(|s| s.as_str())
+        .collect();
+
+    if disallowed.is_empty() {
+        Ok(normalize(requested))
+    } else {
+        Err(format!(
+            "this relay doesn't allow requesting the following scopes: {}",
+            disallowed.join(", ")
+        ))
+    }
+}
+
+pub fn default_scopes(config: &Config) -> Vec<String> {
+    normalize(config.api.scope.iter().cloned())
+}
+
+pub fn union(a: &[String], b: &[String]) -> Vec<String> {
+    normalize(a.iter().chain(b).cloned())
+}
+
+pub fn missing<'a>(granted: &[String], demanded: &'a [String]) -> Vec<&'a str> {
+    demanded
+        .iter()
+        .filter(|s| !granted.contains(s))
+        .map(|s| s.as_str())
+        .collect()
+}
@@ -0,0 +1,76 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Compare two secrets without leaking the length of their common prefix through timing.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Show just enough of a secret to tell it apart from others.
+pub fn mask(secret: &str) -> String {
+    match (
+        secret.get(..4),
+        secret.get(secret.len().saturating_sub(4)..),
+    ) {
+        (Some(head), Some(tail)) if secret.len() > 16 => format!("{}...{}", head, tail),
+        _ => "***".to_owned(),
+    }
+}
+
+const FINGERPRINT_LENGTH: usize = 16;
+
+/// A short stand-in for a secret which is the same every time, so that it can name things without revealing it.
+pub fn fingerprint(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))[..FINGERPRINT_LENGTH].to_owned()
+}
+
+/// Whether the text has the shape of a fingerprint.
+pub fn is_fingerprint(text: &str) -> bool {
+    text.len() == FINGERPRINT_LENGTH && text.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Stands for a session id, token or key in logs and error messages, which need to tell them apart but must not reveal them.
+pub struct Redacted<'a>(pub &'a str);
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", &fingerprint(self.0)[..12])
+    }
+}
+
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+pub fn redact(secret: &str) -> Redacted<'_> {
+    Redacted(secret)
+}
+
+/// A configured secret, which can only be printed redacted.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&redact(&self.0), f)
+    }
+}
@@ -1,39 +1,440 @@
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
 
-use redis::Commands;
+use redis::{AsyncCommands, Commands, ExistenceCheck, SetExpiry, SetOptions};
+use serde::{de::DeserializeOwned, Serialize};
 use sessions::Storage;
 
 use crate::config::Config;
+use crate::handlers::auth::SESSION_FIELD_TOKEN;
+use crate::metrics;
+use crate::secrets::redact;
 
 pub const SESSION_COOKIE_NAME: &str = "session-id";
+/// Sessions without an osu! token only carry a CSRF secret or a pending login, pairing or handoff, all of which expire sooner.
+pub const ANONYMOUS_SESSION_LIFETIME_SECS: u64 = 60 * 60;
+
+pub const PAIRING_KEY_PREFIX: &str = "pairing:";
+pub const PAIRING_CODE_KEY_PREFIX: &str = "pairing-code:";
+pub const EXCHANGE_KEY_PREFIX: &str = "exchange:";
+pub const API_KEY_PREFIX: &str = "api-key:";
+pub const SESSION_API_KEYS_PREFIX: &str = "api-keys:";
+pub const USER_SESSIONS_PREFIX: &str = "user-sessions:";
+pub const APP_TOKEN_PREFIX: &str = "app-token:";
+pub const RATE_LIMIT_PREFIX: &str = "rate-limit:";
+pub const REFRESH_FAILURES_KEY: &str = "refresher:failures";
+/// Sorted set of session ids, scored by when their osu! tokens expire.
+pub const TOKEN_EXPIRY_KEY: &str = "refresher:token-expiry";
+
+/// Takes a token from the bucket in KEYS[1] (capacity ARGV[1], refilled at ARGV[2] tokens per second).
+/// Returns 0 if there was one, or the number of seconds until there will be. Valkey's clock is used, so that all replicas agree.
+const TAKE_TOKEN_SCRIPT: &str = r#"
+local capacity = tonumber(ARGV[1])
+local rate = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) + tonumber(time[2]) / 1000000
+
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+local tokens = tonumber(bucket[1]) or capacity
+local ts = tonumber(bucket[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - ts) * rate)
+
+local wait = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+else
+    wait = math.ceil((1 - tokens) / rate)
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'ts', tostring(now))
+redis.call('EXPIRE', KEYS[1], math.ceil(capacity / rate) + 1)
+return wait
+"#;
+
+/// Auxiliary records are stored under prefixed keys, while session ids never contain a colon.
+pub fn is_session_key(key: &str) -> bool {
+    !key.contains(':')
+}
 
 #[derive(Clone)]
 pub struct ValkeyStorage {
     pub client: redis::Client,
-    cache: Arc<Mutex<HashMap<String, sessions::Data>>>,
+    session_lifetime: std::time::Duration,
 }
 
 impl ValkeyStorage {
     pub fn new(c: &Config) -> Self {
         Self {
             client: redis::Client::open(c.service.valkey.address.to_owned()).unwrap(),
-            cache: Arc::default(),
+            session_lifetime: std::time::Duration::from_secs(c.cookie.session_lifetime_secs),
+        }
+    }
+
+    pub fn session_lifetime(&self) -> std::time::Duration {
+        self.session_lifetime
+    }
+
+    pub async fn ping(&self) -> eyre::Result<()> {
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["ping"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        redis::cmd("PING").query_async::<_, ()>(&mut conn).await?;
+        Ok(())
+    }
+
+    pub async fn get_json<T: DeserializeOwned>(&self, key: &str) -> eyre::Result<Option<T>> {
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["get"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        match conn.get::<&str, Option<String>>(key).await? {
+            Some(v) => Ok(Some(serde_json::from_str(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Save a value as JSON, either with a new TTL in seconds, or keeping the one the key already has (if any).
+    pub async fn set_json<T: Serialize>(
+        &self,
+        key: &str,
+        val: &T,
+        exp: Option<u64>,
+    ) -> eyre::Result<()> {
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["set"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        let serialized = serde_json::to_string(val)?;
+        let expiry = match exp {
+            Some(secs) => SetExpiry::EX(secs as usize),
+            None => SetExpiry::KEEPTTL,
+        };
+        conn.set_options::<&str, String, ()>(
+            key,
+            serialized,
+            SetOptions::default().with_expiration(expiry),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Fetch and remove a value atomically, so that one-time records can't be claimed twice.
+    pub async fn take_json<T: DeserializeOwned>(&self, key: &str) -> eyre::Result<Option<T>> {
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["getdel"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        match conn.get_del::<&str, Option<String>>(key).await? {
+            Some(v) => Ok(Some(serde_json::from_str(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `get_json`, but for many keys in one round trip.
+    pub async fn mget_json<T: DeserializeOwned>(
+        &self,
+        keys: &[String],
+    ) -> eyre::Result<Vec<Option<T>>> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["mget"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        conn.mget::<&[String], Vec<Option<String>>>(keys)
+            .await?
+            .into_iter()
+            .map(|v| match v {
+                Some(v) => Ok(Some(serde_json::from_str(&v)?)),
+                None => Ok(None),
+            })
+            .collect()
+    }
+
+    pub async fn exists(&self, key: &str) -> eyre::Result<bool> {
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["exists"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        Ok(conn.exists::<&str, bool>(key).await?)
+    }
+
+    pub async fn hget_json<T: DeserializeOwned>(
+        &self,
+        key: &str,
+        field: &str,
+    ) -> eyre::Result<Option<T>> {
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["hget"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        match conn.hget::<&str, &str, Option<String>>(key, field).await? {
+            Some(v) => Ok(Some(serde_json::from_str(&v)?)),
+            None => Ok(None),
         }
     }
+
+    pub async fn hset_json<T: Serialize>(
+        &self,
+        key: &str,
+        field: &str,
+        val: &T,
+    ) -> eyre::Result<()> {
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["hset"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        conn.hset::<&str, &str, String, ()>(key, field, serde_json::to_string(val)?)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn hvals_json<T: DeserializeOwned>(&self, key: &str) -> eyre::Result<Vec<T>> {
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["hvals"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        conn.hvals::<&str, Vec<String>>(key)
+            .await?
+            .iter()
+            .map(|v| serde_json::from_str(v).map_err(|e| e.into()))
+            .collect()
+    }
+
+    pub async fn hgetall_json<T: DeserializeOwned>(
+        &self,
+        key: &str,
+    ) -> eyre::Result<HashMap<String, T>> {
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["hgetall"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        conn.hgetall::<&str, HashMap<String, String>>(key)
+            .await?
+            .into_iter()
+            .map(|(field, v)| Ok((field, serde_json::from_str(&v)?)))
+            .collect()
+    }
+
+    pub async fn hdel(&self, key: &str, field: &str) -> eyre::Result<()> {
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["hdel"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        conn.hdel::<&str, &str, ()>(key, field).await?;
+        Ok(())
+    }
+
+    /// Prepend a value to a list, keeping only the `max_len` newest ones.
+    pub async fn push_json<T: Serialize>(
+        &self,
+        key: &str,
+        value: &T,
+        max_len: isize,
+    ) -> eyre::Result<()> {
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["lpush"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        redis::pipe()
+            .lpush(key, serde_json::to_string(value)?)
+            .ltrim(key, 0, max_len - 1)
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn range_json<T: DeserializeOwned>(&self, key: &str) -> eyre::Result<Vec<T>> {
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["lrange"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        conn.lrange::<&str, Vec<String>>(key, 0, -1)
+            .await?
+            .iter()
+            .map(|v| serde_json::from_str(v).map_err(|e| e.into()))
+            .collect()
+    }
+
+    /// All keys starting with the prefix, with the prefix stripped.
+    pub async fn keys_with_prefix(&self, prefix: &str) -> eyre::Result<Vec<String>> {
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["keys"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        let keys = conn
+            .keys::<String, Vec<String>>(format!("{}*", prefix))
+            .await?;
+        Ok(keys
+            .into_iter()
+            .filter_map(|k| k.strip_prefix(prefix).map(str::to_owned))
+            .collect())
+    }
+
+    pub async fn session_keys(&self) -> eyre::Result<Vec<String>> {
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["keys"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        let mut keys = conn.keys::<&str, Vec<String>>("*").await?;
+        keys.retain(|k| is_session_key(k));
+        Ok(keys)
+    }
+
+    /// Seconds until the key expires, or `None` if it doesn't exist or never expires.
+    pub async fn ttl(&self, key: &str) -> eyre::Result<Option<i64>> {
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["ttl"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        let ttl = conn.ttl::<&str, i64>(key).await?;
+        Ok((ttl >= 0).then_some(ttl))
+    }
+
+    /// Like `ttl`, but for many keys in one round trip.
+    pub async fn ttls(&self, keys: &[String]) -> eyre::Result<Vec<Option<i64>>> {
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["ttl"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        let mut pipe = redis::pipe();
+        for key in keys {
+            pipe.ttl(key);
+        }
+        let ttls = pipe.query_async::<_, Vec<i64>>(&mut conn).await?;
+        Ok(ttls
+            .into_iter()
+            .map(|ttl| (ttl >= 0).then_some(ttl))
+            .collect())
+    }
+
+    /// Reset the TTL of a session, since it has just been used.
+    pub async fn touch_session(&self, key: &str) -> eyre::Result<()> {
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["expire"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        conn.expire::<&str, ()>(key, self.session_lifetime.as_secs() as i64)
+            .await?;
+        Ok(())
+    }
+
+    /// Save a session without extending its lifetime, e.g. when it's changed in the background.
+    /// A session which has been removed in the meantime stays removed.
+    pub async fn update_session(&self, key: &str, val: &sessions::Data) -> eyre::Result<()> {
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["set"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        conn.set_options::<&str, String, ()>(
+            key,
+            serde_json::to_string(val)?,
+            SetOptions::default()
+                .conditional_set(ExistenceCheck::XX)
+                .with_expiration(SetExpiry::KEEPTTL),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn zadd(&self, key: &str, member: &str, score: i64) -> eyre::Result<()> {
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["zadd"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        conn.zadd::<&str, i64, &str, ()>(key, member, score).await?;
+        Ok(())
+    }
+
+    /// Like `zadd`, but for many members at once, and without changing the score of the ones already in the set.
+    pub async fn zadd_new(&self, key: &str, members: &[(i64, String)]) -> eyre::Result<()> {
+        if members.is_empty() {
+            return Ok(());
+        }
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["zadd"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        redis::cmd("ZADD")
+            .arg(key)
+            .arg("NX")
+            .arg(members)
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn zrem(&self, key: &str, members: &[String]) -> eyre::Result<()> {
+        if members.is_empty() {
+            return Ok(());
+        }
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["zrem"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        conn.zrem::<&str, &[String], ()>(key, members).await?;
+        Ok(())
+    }
+
+    /// Members with scores up to `max`, lowest first.
+    pub async fn zrange_up_to(&self, key: &str, max: i64) -> eyre::Result<Vec<String>> {
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["zrangebyscore"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        Ok(conn
+            .zrangebyscore::<&str, &str, i64, Vec<String>>(key, "-inf", max)
+            .await?)
+    }
+
+    /// Take a token from a rate limiting bucket. Returns `None` if there was one, or else the seconds until there will be.
+    pub async fn take_token(
+        &self,
+        key: &str,
+        capacity: u32,
+        per_second: f64,
+    ) -> eyre::Result<Option<u64>> {
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["evalsha"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        let wait = redis::Script::new(TAKE_TOKEN_SCRIPT)
+            .key(key)
+            .arg(capacity)
+            .arg(per_second)
+            .invoke_async::<_, u64>(&mut conn)
+            .await?;
+        Ok((wait > 0).then_some(wait))
+    }
+
+    pub async fn delete(&self, keys: &[&str]) -> eyre::Result<()> {
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["del"])
+            .start_timer();
+        let mut conn = self.client.get_multiplexed_tokio_connection().await?;
+        conn.del::<&[&str], ()>(keys).await?;
+        Ok(())
+    }
 }
 
 impl Storage for ValkeyStorage {
     async fn get(&self, key: &str) -> std::io::Result<Option<sessions::Data>> {
-        log::debug!("Loading session: {}", key);
-
-        if let Some(v) = self.cache.lock().unwrap().get(key) {
-            return Ok(Some(v.clone()));
-        }
+        log::debug!("Loading session: {}", redact(key));
 
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["get"])
+            .start_timer();
         let mut conn = self.client.get_connection().unwrap();
-        match conn.get::<&str, String>(key) {
-            redis::RedisResult::Ok(v) => match serde_json::from_str(&v) {
+        // A session which has expired or was revoked is simply gone, and the browser gets a new one.
+        let result = conn.get::<&str, Option<String>>(key);
+        if let Ok(value) = &result {
+            metrics::SESSION_LOOKUPS
+                .with_label_values(&[if value.is_some() { "hit" } else { "miss" }])
+                .inc();
+        }
+        match result {
+            redis::RedisResult::Ok(None) => Ok(None),
+            redis::RedisResult::Ok(Some(v)) => match serde_json::from_str(&v) {
                 Ok(loaded) => Ok(Some(loaded)),
                 Err(e) => {
                     log::error!("Error while deserializing key from Valkey: {}", e);
@@ -42,24 +443,31 @@ impl Storage for ValkeyStorage {
             },
             redis::RedisResult::Err(e) => {
                 log::error!("Error while loading key from Valkey: {}", e);
-                Err(std::io::Error::new(std::io::ErrorKind::Other, e))
+                Err(std::io::Error::other(e))
             }
         }
     }
 
+    /// Logged in sessions are kept for the configured lifetime, whatever Max-Age the cookie has (which viz passes as `exp`).
+    /// Anonymous ones only last long enough to finish logging in, so that visitors who never do can't fill up Valkey.
     async fn set(
         &self,
         key: &str,
         val: sessions::Data,
         exp: &std::time::Duration,
     ) -> std::io::Result<()> {
-        log::debug!("Saving session: {} (exp: {:?})", key, exp);
-
-        self.cache
-            .lock()
-            .unwrap()
-            .insert(key.to_owned(), val.clone());
+        let exp = if val.contains_key(SESSION_FIELD_TOKEN) {
+            self.session_lifetime
+        } else {
+            (*exp).min(std::time::Duration::from_secs(
+                ANONYMOUS_SESSION_LIFETIME_SECS,
+            ))
+        };
+        log::debug!("Saving session: {} (exp: {:?})", redact(key), exp);
 
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["set"])
+            .start_timer();
         let mut conn = self.client.get_connection().unwrap();
         match serde_json::to_string(&val) {
             Err(e) => {
@@ -78,14 +486,21 @@ impl Storage for ValkeyStorage {
     }
 
     async fn remove(&self, key: &str) -> std::io::Result<()> {
-        log::debug!("removing session: {}", key);
-
-        self.cache.lock().unwrap().remove(key);
+        log::debug!("removing session: {}", redact(key));
 
+        let _timer = metrics::VALKEY_COMMAND_DURATION
+            .with_label_values(&["del"])
+            .start_timer();
         let mut conn = self.client.get_connection().unwrap();
         if let Err(e) = conn.del::<&str, ()>(key) {
             log::error!("Error while deleting key from Valkey: {}", e);
         }
+        if let Err(e) = conn.zrem::<&str, &str, ()>(TOKEN_EXPIRY_KEY, key) {
+            log::error!(
+                "Error while unscheduling a removed session in Valkey: {}",
+                e
+            );
+        }
 
         Ok(())
     }
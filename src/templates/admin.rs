@@ -0,0 +1,144 @@
+use super::auth::{format_timestamp, BaseTemplate, CsrfField};
+use crate::model::{AdminOverview, SessionSummary};
+
+markup::define! {
+    AdminDashboardPage<'a>(
+        overview: AdminOverview,
+        last_sweep: Option<i64>,
+        refresher_alive: bool,
+        sessions_url: &'a str
+    ) {
+        @BaseTemplate {
+            title: "admin",
+            content: _AdminDashboardContent { overview, last_sweep, refresher_alive, sessions_url }
+        }
+    }
+
+    _AdminDashboardContent<'a>(
+        overview: &'a AdminOverview,
+        last_sweep: &'a Option<i64>,
+        refresher_alive: &'a bool,
+        sessions_url: &'a str
+    ) {
+        h2 { "Overview" }
+        p {
+            "Sessions: " b { @overview.total_sessions } br { }
+            "Tokens expiring within the refresh threshold: " b { @overview.expiring_soon } br { }
+            "Refresher: " b { @if **refresher_alive { "running" } else { "stuck" } }
+            ", last sweep: "
+            @if let Some(ts) = last_sweep { @format_timestamp(*ts) } else { "never" }
+        }
+
+        h3 { "Expiring soon" }
+        @if overview.expiring.is_empty() {
+            p { "No sessions are about to expire." }
+        } else {
+            @_SessionTable { sessions: &overview.expiring, sessions_url }
+        }
+
+        h3 { "Recent refresh failures" }
+        @if overview.failures.is_empty() {
+            p { "No failures recorded." }
+        } else {
+            table {
+                thead { tr { th { "When" } th { "Session" } th { "Error" } } }
+                tbody {
+                    @for failure in overview.failures.iter() {
+                        tr {
+                            td { @format_timestamp(failure.ctime) }
+                            td {
+                                @if failure.session.is_empty() {
+                                    "unknown"
+                                } else {
+                                    a[href = format!("{}/{}", sessions_url, failure.session)] {
+                                        code { @failure.session[..8] }
+                                    }
+                                }
+                            }
+                            td { @failure.error }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    _SessionTable<'a>(sessions: &'a Vec<SessionSummary>, sessions_url: &'a str) {
+        table {
+            thead { tr { th { "Session" } th { "User" } th { "App" } th { "Token expires in" } th { "Session expires in" } } }
+            tbody {
+                @for session in sessions.iter() {
+                    tr {
+                        td {
+                            a[href = format!("{}/{}", sessions_url, session.fingerprint)] {
+                                code { @session.fingerprint[..8] }
+                            }
+                        }
+                        td {
+                            @if let Some(user) = &session.user {
+                                @user.username " (#" @user.user_id ")"
+                            } else {
+                                "unknown"
+                            }
+                        }
+                        td { @session.app.as_deref().unwrap_or("default") }
+                        td {
+                            @if let Some(exp) = session.token_expires_in { @exp "s" } else { "no token" }
+                        }
+                        td {
+                            @if let Some(ttl) = session.ttl { @ttl "s" } else { "never" }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    AdminSessionPage<'a>(
+        session: SessionSummary,
+        metadata: String,
+        notice: Option<&'a str>,
+        dashboard_url: &'a str,
+        refresh_url: &'a str,
+        revoke_url: &'a str,
+        csrf_token: &'a str
+    ) {
+        @BaseTemplate {
+            title: "admin",
+            content: _AdminSessionContent { session, metadata, notice, dashboard_url, refresh_url, revoke_url, csrf_token }
+        }
+    }
+
+    _AdminSessionContent<'a>(
+        session: &'a SessionSummary,
+        metadata: &'a String,
+        notice: &'a Option<&'a str>,
+        dashboard_url: &'a str,
+        refresh_url: &'a str,
+        revoke_url: &'a str,
+        csrf_token: &'a str
+    ) {
+        h2 { "Session " code { @session.fingerprint[..8] } }
+        @if let Some(notice) = notice {
+            p { mark { @notice } }
+        }
+        p {
+            @if let Some(user) = &session.user {
+                "User: " b { @user.username " (#" @user.user_id ")" } br { }
+            }
+            "App: " @session.app.as_deref().unwrap_or("default") br { }
+            "Token expires in: " @if let Some(exp) = session.token_expires_in { @exp "s" } else { "no token" } br { }
+            "Session expires in: " @if let Some(ttl) = session.ttl { @ttl "s" } else { "never" }
+        }
+        pre { code { @metadata } }
+        form[method = "post", action = refresh_url] {
+            @CsrfField { token: csrf_token }
+            button[type = "submit"] { "Refresh the token" }
+        }
+        form[method = "post", action = revoke_url] {
+            @CsrfField { token: csrf_token }
+            button[type = "submit"] { "Log the session out" }
+        }
+        p { a[href = dashboard_url] { "Back to the dashboard" } }
+    }
+}
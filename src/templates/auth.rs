@@ -1,6 +1,9 @@
+use chrono::DateTime;
 use markup::{self, Render};
 
-use crate::model::{AccessToken, UserCompact};
+use super::footer_override;
+use crate::middleware::CSRF_FORM_FIELD;
+use crate::model::{AccessToken, ApiKey, Device, NewApiKey, UserCompact};
 
 const WEBSITE_TITLE: &str = "relay";
 
@@ -8,9 +11,23 @@ pub trait MarkupTemplate {
     fn to_string(&self) -> String;
 }
 
+pub(super) fn format_timestamp(ts: i64) -> String {
+    DateTime::from_timestamp(ts, 0)
+        .map(|dt| dt.to_string())
+        .unwrap_or_default()
+}
+
 markup::define! {
     Footer() {
-        footer { small { "relay | uses " a[href = "https://github.com/andybrewer/mvp/" ] { "MVP.css" } } }
+        @if let Some(footer) = footer_override() {
+            @markup::raw(footer)
+        } else {
+            footer { small { "relay" } }
+        }
+    }
+
+    CsrfField<'a>(token: &'a str) {
+        input[type = "hidden", name = CSRF_FORM_FIELD, value = token];
     }
 
     BaseTemplate<'a, Content: Render>(title: &'a str, content: Content) {
@@ -19,7 +36,8 @@ markup::define! {
         html {
             head {
                 title { @title " – " @WEBSITE_TITLE }
-                link [rel = "stylesheet", href = "https://unpkg.com/mvp.css" ] {}
+                link [rel = "stylesheet", href = "/static/relay.css" ] {}
+                link [rel = "stylesheet", href = "/static/theme.css" ] {}
             }
         }
         body {
@@ -33,17 +51,49 @@ markup::define! {
 }
 
 markup::define! {
-    AuthSuccessPage<'a>(data: UserCompact, token: AccessToken, session_id: &'a str, logout_url: &'a str) {
+    AuthSuccessPage<'a>(
+        data: UserCompact,
+        token: AccessToken,
+        keys: Vec<ApiKey>,
+        devices: Vec<Device>,
+        new_key: Option<&'a NewApiKey>,
+        notice: Option<&'a str>,
+        profile_url: &'a str,
+        keys_url: &'a str,
+        revoke_key_url: &'a str,
+        revoke_device_url: &'a str,
+        logout_url: &'a str,
+        csrf_token: &'a str
+    ) {
         @BaseTemplate {
             title: "authentication",
-            content: _AuthSuccessContent { data, token, session_id, logout_url }
+            content: _AuthSuccessContent {
+                data, token, keys, devices, new_key, notice, profile_url, keys_url, revoke_key_url, revoke_device_url, logout_url,
+                csrf_token
+            }
         }
     }
 
-    _AuthSuccessContent<'a> (data: &'a UserCompact, token: &'a AccessToken, session_id: &'a str, logout_url: &'a str) {
+    _AuthSuccessContent<'a> (
+        data: &'a UserCompact,
+        token: &'a AccessToken,
+        keys: &'a Vec<ApiKey>,
+        devices: &'a Vec<Device>,
+        new_key: &'a Option<&'a NewApiKey>,
+        notice: &'a Option<&'a str>,
+        profile_url: &'a str,
+        keys_url: &'a str,
+        revoke_key_url: &'a str,
+        revoke_device_url: &'a str,
+        logout_url: &'a str,
+        csrf_token: &'a str
+    ) {
         h2 { "Status" }
+        @if let Some(notice) = notice {
+            p { mark { @notice } }
+        }
         p {
-            a[href = format!("https://osu.ppy.sh/users/{}", data.user_id)] {
+            a[href = profile_url] {
                 img[
                     src = &data.avatar_url,
                     alt = "your avatar",
@@ -55,10 +105,79 @@ markup::define! {
             "Current osu! API token: " code {
                 @token.access_token[0..8] "..." @token.access_token[token.access_token.len() - 8..]
             } "(obtained at: " @token.obtained_at().to_string() ", expires in: " @token.lifetime() " seconds)"
-            br { }
-            "Your relay session identifier: " code { @session_id }
-            br { }
-            a[href = logout_url] { b { "Log out" } }
+        }
+        form[method = "post", action = logout_url] {
+            @CsrfField { token: csrf_token }
+            button[type = "submit"] { "Log out" }
+        }
+
+        h3 { "API keys" }
+        @if let Some(key) = new_key {
+            p {
+                mark { "New key for " @key.label ": " code { @key.key } }
+                br { }
+                small { "Copy it now -- it won't be shown again." }
+            }
+        }
+        @if keys.is_empty() {
+            p { "You have no API keys yet." }
+        } else {
+            table {
+                thead { tr { th { "Label" } th { "Created" } th { "Last used" } th { } } }
+                tbody {
+                    @for key in keys.iter() {
+                        tr {
+                            td { @key.label }
+                            td { @format_timestamp(key.ctime) }
+                            td {
+                                @if let Some(last_used) = key.last_used {
+                                    @format_timestamp(last_used)
+                                } else {
+                                    "never"
+                                }
+                            }
+                            td {
+                                form[method = "post", action = revoke_key_url] {
+                                    @CsrfField { token: csrf_token }
+                                    input[type = "hidden", name = "id", value = &key.id];
+                                    button[type = "submit"] { "Revoke" }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        form[method = "post", action = keys_url] {
+            @CsrfField { token: csrf_token }
+            label[for = "label"] { "Label" }
+            input[type = "text", id = "label", name = "label", placeholder = "e.g. steel on my laptop", maxlength = 64];
+            button[type = "submit"] { "Create API key" }
+        }
+
+        h3 { "Devices" }
+        table {
+            thead { tr { th { "Browser" } th { "Clients" } th { "Logged in" } th { "Last used" } th { } } }
+            tbody {
+                @for device in devices.iter() {
+                    tr {
+                        td {
+                            @device.user_agent.as_deref().unwrap_or("unknown")
+                            @if device.current { " " b { "(this one)" } }
+                        }
+                        td { @device.clients.join(", ") }
+                        td { @format_timestamp(device.ctime) }
+                        td { @format_timestamp(device.last_used) }
+                        td {
+                            form[method = "post", action = revoke_device_url] {
+                                @CsrfField { token: csrf_token }
+                                input[type = "hidden", name = "id", value = &device.id];
+                                button[type = "submit"] { "Log out" }
+                            }
+                        }
+                    }
+                }
+            }
         }
     }
 }
@@ -80,18 +199,86 @@ markup::define! {
 }
 
 markup::define! {
-    AuthErrorPage<'a>(error: &'a str, logout_url: &'a str) {
+    AuthErrorPage<'a>(
+        heading: &'a str,
+        error: &'a str,
+        request_id: &'a str,
+        logout_url: &'a str,
+        csrf_token: &'a str
+    ) {
         @BaseTemplate {
             title: "authentication error",
-            content: _AuthErrorContent { error, logout_url }
+            content: _AuthErrorContent { heading, error, request_id, logout_url, csrf_token }
+        }
+    }
+
+    _AuthErrorContent<'a>(
+        heading: &'a str,
+        error: &'a str,
+        request_id: &'a str,
+        logout_url: &'a str,
+        csrf_token: &'a str
+    ) {
+        h2 { @heading }
+        aside { @error }
+        p { small { "Request id: " code { @request_id } } }
+        form[method = "post", action = logout_url] {
+            @CsrfField { token: csrf_token }
+            button[type = "submit"] { "Try again" }
+        }
+    }
+}
+
+markup::define! {
+    AuthPairingPage<'a>(user_code: &'a str, submit_url: &'a str, csrf_token: &'a str) {
+        @BaseTemplate {
+            title: "pairing",
+            content: _AuthPairingContent { user_code, submit_url, csrf_token }
+        }
+    }
+
+    _AuthPairingContent<'a>(user_code: &'a str, submit_url: &'a str, csrf_token: &'a str) {
+        h2 { "Pair a client" }
+        form[method = "post", action = submit_url] {
+            @CsrfField { token: csrf_token }
+            label[for = "user_code"] { "Enter the code shown by your client:" }
+            input[type = "text", id = "user_code", name = "user_code", value = user_code, placeholder = "XXXX-XXXX", autocomplete = "off", required];
+            button[type = "submit"] { "Continue" }
         }
     }
+}
 
-    _AuthErrorContent<'a>(error: &'a str, logout_url: &'a str) {
-        h2 { "Authentication error" }
+markup::define! {
+    AuthHandoffPage<'a>(
+        port: u16,
+        return_to: &'a str,
+        nonce: &'a str,
+        submit_url: &'a str,
+        csrf_token: &'a str
+    ) {
+        @BaseTemplate {
+            title: "handoff",
+            content: _AuthHandoffContent { port, return_to, nonce, submit_url, csrf_token }
+        }
+    }
+
+    _AuthHandoffContent<'a>(
+        port: &'a u16,
+        return_to: &'a str,
+        nonce: &'a str,
+        submit_url: &'a str,
+        csrf_token: &'a str
+    ) {
+        h2 { "Log in a client" }
         p {
-            aside { @error }
-            a[href = logout_url] { b { "Try again" } }
+            "A program on this computer, listening on port " code { @port } ", asks for access to your osu! account. "
+            "Only continue if you've just started logging in to it yourself."
+        }
+        form[method = "post", action = submit_url] {
+            @CsrfField { token: csrf_token }
+            input[type = "hidden", name = "return_to", value = return_to];
+            input[type = "hidden", name = "nonce", value = nonce];
+            button[type = "submit"] { "Continue" }
         }
     }
 }
@@ -1 +1,22 @@
+use std::path::Path;
+use std::sync::OnceLock;
+
+pub mod admin;
 pub mod auth;
+
+const FOOTER_TEMPLATE: &str = "footer.html";
+
+/// HTML replacing the built-in footer, if the theme has one.
+static FOOTER_OVERRIDE: OnceLock<String> = OnceLock::new();
+
+pub fn load_theme(theme_dir: &str) -> eyre::Result<()> {
+    let footer = Path::new(theme_dir).join(FOOTER_TEMPLATE);
+    if footer.is_file() {
+        let _ = FOOTER_OVERRIDE.set(std::fs::read_to_string(footer)?);
+    }
+    Ok(())
+}
+
+fn footer_override() -> Option<&'static str> {
+    FOOTER_OVERRIDE.get().map(String::as_str)
+}
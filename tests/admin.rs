@@ -0,0 +1,43 @@
+//! Opens the admin pages as an operator and checks that sessions are only named by their fingerprints.
+
+mod common;
+
+use common::{get_page, log_in, Harness};
+use reqwest::StatusCode;
+use sha2::{Digest, Sha256};
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs RELAY_TEST_VALKEY"]
+async fn admin_pages_name_sessions_by_fingerprint() {
+    let harness = Harness::new().await;
+    let sessions_before = harness.session_keys();
+    let _relay = harness.start("relay.log").await;
+    let cookie = log_in(&harness.base).await;
+    let session_id = harness
+        .session_keys()
+        .into_iter()
+        .find(|k| !sessions_before.contains(k))
+        .expect("the login didn't create a session");
+    let fingerprint = format!("{:x}", Sha256::digest(session_id.as_bytes()))[..16].to_owned();
+
+    let dashboard = get_page(&harness.base, "/admin", &cookie).await;
+    assert_eq!(dashboard.status(), StatusCode::OK);
+    assert!(!dashboard.text().await.unwrap().contains(&session_id));
+
+    for id in [&fingerprint, &session_id] {
+        let page = get_page(&harness.base, &format!("/admin/sessions/{}", id), &cookie).await;
+        assert_eq!(page.status(), StatusCode::OK);
+        let html = page.text().await.unwrap();
+        assert!(html.contains(&format!("/admin/sessions/{}/revoke", fingerprint)));
+        assert!(!html.contains(&session_id), "the page shows the session id");
+    }
+
+    for id in [
+        "not-a-session",
+        "0123456789abcdef",
+        &fingerprint.to_uppercase(),
+    ] {
+        let page = get_page(&harness.base, &format!("/admin/sessions/{}", id), &cookie).await;
+        assert_eq!(page.status(), StatusCode::NOT_FOUND, "{:?} was found", id);
+    }
+}
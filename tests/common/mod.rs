@@ -0,0 +1,376 @@
+//! A stand-in osu! server and helpers to run relay against it.
+//!
+//! Tests using these need a disposable Valkey instance: set `RELAY_TEST_VALKEY` to its address (e.g. `redis://localhost:6379/15`)
+//! and run them with `cargo test -- --ignored`.
+
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use redis::Commands;
+use viz::{serve, IntoResponse, Request, RequestExt, Response, ResponseExt, Router, StatusCode};
+
+pub const CLIENT_SECRET: &str = "client-secret-4f0c2b9e8a7d6c5b";
+pub const COOKIE_KEY: &str = "9a8b7c6d5e4f30211203f4e5d6c7b8a99a8b7c6d5e4f30211203f4e5d6c7b8a99a8b7c6d5e4f30211203f4e5d6c7b8a99a8b7c6d5e4f30211203f4e5d6c7b8a9";
+pub const SESSION_COOKIE_NAME: &str = "session-id";
+
+/// Every token handed out by the stand-in osu! server, and the grants they were asked for with.
+#[derive(Clone, Default)]
+pub struct Issued {
+    tokens: Arc<Mutex<Vec<String>>>,
+    grants: Arc<Mutex<Vec<String>>>,
+}
+
+impl Issued {
+    fn mint(&self, kind: &str) -> String {
+        let token = format!("{}-{}", kind, nanoid::nanoid!(32));
+        self.tokens.lock().unwrap().push(token.clone());
+        token
+    }
+
+    pub fn tokens(&self) -> Vec<String> {
+        self.tokens.lock().unwrap().clone()
+    }
+
+    pub fn grants(&self, grant_type: &str) -> usize {
+        self.grants
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|g| *g == grant_type)
+            .count()
+    }
+}
+
+async fn token(mut r: Request) -> viz::Result<Response> {
+    let issued = r.state::<Issued>().unwrap();
+    let form = r.form::<HashMap<String, String>>().await?;
+    let grant_type = form.get("grant_type").cloned().unwrap_or_default();
+    issued.grants.lock().unwrap().push(grant_type.clone());
+    if form.get("client_secret").map(String::as_str) != Some(CLIENT_SECRET) {
+        return Ok((
+            StatusCode::UNAUTHORIZED,
+            Response::json(serde_json::json!({
+                "error": "invalid_client",
+                "error_description": "Client authentication failed",
+            }))?,
+        )
+            .into_response());
+    }
+    let mut body = serde_json::json!({
+        "access_token": issued.mint("access"),
+        "expires_in": 3600,
+        "token_type": "Bearer",
+    });
+    if grant_type != "client_credentials" {
+        body["refresh_token"] = issued.mint("refresh").into();
+    }
+    Ok(Response::json(body)?)
+}
+
+async fn me(_: Request) -> viz::Result<Response> {
+    Ok(Response::json(serde_json::json!({
+        "id": 2,
+        "username": "peppy",
+        "avatar_url": "https://a.ppy.sh/2",
+    }))?)
+}
+
+async fn start_osu(issued: Issued) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = Router::new()
+        .post("/oauth/token", token)
+        .get("/api/v2/me", me)
+        .with(viz::types::State::new(issued));
+    tokio::spawn(async move { serve(listener, app).await });
+    format!("http://{}", addr)
+}
+
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn write_config(dir: &Path, osu_url: &str, port: u16, valkey: &str) {
+    let config = format!(
+        r#"api:
+  base_url: {osu_url}
+  apps:
+  - name: test
+    client_id: 1
+    client_secret: {CLIENT_SECRET}
+    redirect_url: http://127.0.0.1:{port}/auth
+  scope: [identify, public]
+service:
+  bind_host: 127.0.0.1
+  bind_port: {port}
+  max_concurrent_requests: 16
+  cookie_key: {COOKIE_KEY}
+  valkey:
+    address: {valkey}
+admin:
+  user_ids: [2]
+cookie:
+  secure: false
+"#
+    );
+    std::fs::write(dir.join("config.yaml"), config).unwrap();
+}
+
+/// A config for relay in a scratch directory, pointing to a fresh stand-in osu! server.
+pub struct Harness {
+    pub dir: PathBuf,
+    pub issued: Issued,
+    pub base: String,
+    pub valkey: String,
+}
+
+impl Harness {
+    pub async fn new() -> Self {
+        let valkey = std::env::var("RELAY_TEST_VALKEY")
+            .expect("RELAY_TEST_VALKEY must point to a disposable Valkey instance");
+        let dir = std::env::temp_dir().join(format!("relay-test-{}", nanoid::nanoid!(8)));
+        std::fs::create_dir_all(&dir).unwrap();
+        let issued = Issued::default();
+        let osu_url = start_osu(issued.clone()).await;
+        let port = free_port();
+        write_config(&dir, &osu_url, port, &valkey);
+        Self {
+            dir,
+            issued,
+            base: format!("http://127.0.0.1:{}", port),
+            valkey,
+        }
+    }
+
+    /// Start relay, logging into `log_name` in the scratch directory, and wait until it listens.
+    pub async fn start(&self, log_name: &str) -> Relay {
+        let relay = Relay::start(&self.dir, log_name);
+        for _ in 0..100 {
+            if reqwest::get(format!("{}/healthz", self.base)).await.is_ok() {
+                return relay;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("relay didn't start listening:\n{}", relay.output());
+    }
+
+    /// Change the config for the next start of relay.
+    fn edit_config(&self, from: &str, to: &str) {
+        let path = self.dir.join("config.yaml");
+        let config = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(path, config.replace(from, to)).unwrap();
+    }
+
+    /// Make relay authenticate with another client secret from its next start on.
+    pub fn set_client_secret(&self, secret: &str) {
+        self.edit_config(CLIENT_SECRET, secret);
+    }
+
+    /// Set up the `service.rate_limit` section, given in YAML flow style.
+    pub fn set_rate_limit(&self, rate_limit: &str) {
+        self.edit_config(
+            "  max_concurrent_requests: 16\n",
+            &format!(
+                "  max_concurrent_requests: 16\n  rate_limit: {}\n",
+                rate_limit
+            ),
+        );
+    }
+
+    pub fn logs(&self, names: &[&str]) -> String {
+        names
+            .iter()
+            .map(|name| std::fs::read_to_string(self.dir.join(name)).unwrap())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn session_keys(&self) -> Vec<String> {
+        let mut conn = redis::Client::open(self.valkey.as_str())
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let mut keys: Vec<String> = conn.keys("*").unwrap();
+        keys.retain(|k| !k.contains(':'));
+        keys
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+pub struct Relay {
+    child: Child,
+    log: PathBuf,
+}
+
+impl Relay {
+    fn start(dir: &Path, name: &str) -> Self {
+        let log = dir.join(name);
+        let child = Command::new(env!("CARGO_BIN_EXE_relay"))
+            .current_dir(dir)
+            .env("RUST_LOG", "debug")
+            .stdout(Stdio::null())
+            .stderr(std::fs::File::create(&log).unwrap())
+            .spawn()
+            .unwrap();
+        Self { child, log }
+    }
+
+    pub fn output(&self) -> String {
+        std::fs::read_to_string(&self.log).unwrap_or_default()
+    }
+
+    pub async fn wait_for(&self, needle: &str) {
+        for _ in 0..100 {
+            if self.output().contains(needle) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("relay never logged {:?}:\n{}", needle, self.output());
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub fn client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+}
+
+/// The `name=value` pair of the session cookie set by a response, if any.
+pub fn session_cookie(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get_all("set-cookie")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .find(|v| v.starts_with(&format!("{}=", SESSION_COOKIE_NAME)))
+        .and_then(|v| v.split(';').next())
+        .map(str::to_owned)
+}
+
+/// The value of the first hidden input named `name` after `after` on a page.
+pub fn form_value(html: &str, after: &str, name: &str) -> Option<String> {
+    let rest = &html[html.find(after)?..];
+    let input = format!("name=\"{}\" value=\"", name);
+    let rest = &rest[rest.find(&input)? + input.len()..];
+    rest.split('"').next().map(str::to_owned)
+}
+
+pub async fn get_page(base: &str, path: &str, cookie: &str) -> reqwest::Response {
+    client()
+        .get(format!("{}{}", base, path))
+        .header("cookie", cookie)
+        .send()
+        .await
+        .unwrap()
+}
+
+/// Submit a form of the `/auth` page, along with its CSRF token.
+pub async fn post_form(
+    base: &str,
+    path: &str,
+    cookie: &str,
+    fields: &[(&str, &str)],
+) -> reqwest::Response {
+    let page = get_page(base, "/auth", cookie).await.text().await.unwrap();
+    let csrf_token = form_value(&page, "<form", "csrf_token").expect("no CSRF token on the page");
+    let mut form = vec![("csrf_token", csrf_token.as_str())];
+    form.extend_from_slice(fields);
+    client()
+        .post(format!("{}{}", base, path))
+        .header("cookie", cookie)
+        .form(&form)
+        .send()
+        .await
+        .unwrap()
+}
+
+/// Log in the way a browser would: open the login page, then come back from osu! with a code.
+pub async fn log_in(base: &str) -> String {
+    let page = client().get(format!("{}/auth", base)).send().await.unwrap();
+    let cookie = session_cookie(&page).expect("no session cookie");
+    let html = page.text().await.unwrap();
+    let state = html
+        .split("state=")
+        .nth(1)
+        .and_then(|rest| rest.split(['&', '"']).next())
+        .expect("no login state on the page")
+        .to_owned();
+
+    let callback = get_page(
+        base,
+        &format!("/auth?code=authorization-code&state={}", state),
+        &cookie,
+    )
+    .await;
+    assert_eq!(callback.status(), reqwest::StatusCode::FOUND);
+    let page = get_page(base, "/auth", &cookie).await.text().await.unwrap();
+    assert!(page.contains("peppy"), "login failed:\n{}", page);
+    cookie
+}
+
+/// Call one of the JSON endpoints of the API, returning the status and the body, if any.
+pub async fn post_json(
+    base: &str,
+    path: &str,
+    body: serde_json::Value,
+) -> (reqwest::StatusCode, serde_json::Value) {
+    let response = client()
+        .post(format!("{}{}", base, path))
+        .header("content-type", "application/json")
+        .body(body.to_string())
+        .send()
+        .await
+        .unwrap();
+    let status = response.status();
+    let text = response.text().await.unwrap();
+    (status, serde_json::from_str(&text).unwrap_or_default())
+}
+
+/// Confirm a handoff to a client at `return_to` in a logged-in session, returning the exchange code it gets.
+pub async fn hand_off(base: &str, cookie: &str, return_to: &str, nonce: &str) -> String {
+    let query = serde_urlencoded::to_string([("return_to", return_to), ("nonce", nonce)]).unwrap();
+    let page = get_page(base, &format!("/auth?{}", query), cookie).await;
+    assert_eq!(page.status(), reqwest::StatusCode::OK);
+    let confirmed = post_form(
+        base,
+        "/auth/handoff",
+        cookie,
+        &[("return_to", return_to), ("nonce", nonce)],
+    )
+    .await;
+    assert!(confirmed.status().is_redirection());
+
+    let redirect = get_page(base, "/auth", cookie).await;
+    assert_eq!(redirect.status(), reqwest::StatusCode::FOUND);
+    let location = redirect.headers()["location"].to_str().unwrap();
+    assert!(location.starts_with(return_to), "sent to {}", location);
+    reqwest::Url::parse(location)
+        .unwrap()
+        .query_pairs()
+        .find(|(k, _)| k == "code")
+        .map(|(_, v)| v.into_owned())
+        .expect("no exchange code in the handoff redirect")
+}
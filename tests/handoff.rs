@@ -0,0 +1,97 @@
+//! Hands logged-in sessions over to clients on the same machine, which only happens once the user confirms it.
+
+mod common;
+
+use common::{client, get_page, log_in, post_form, post_json, Harness};
+use reqwest::StatusCode;
+
+const RETURN_TO: &str = "http://127.0.0.1:4567/callback";
+const NONCE: &str = "handoff-nonce-0b9c8d7e";
+
+fn handoff_path(return_to: &str) -> String {
+    format!(
+        "/auth?{}",
+        serde_urlencoded::to_string([("return_to", return_to), ("nonce", NONCE)]).unwrap()
+    )
+}
+
+/// The session is still on the `/auth` page, rather than on its way to a client.
+async fn assert_not_handed_off(base: &str, cookie: &str) {
+    let page = get_page(base, "/auth", cookie).await;
+    assert_eq!(page.status(), StatusCode::OK);
+    assert!(page.text().await.unwrap().contains("peppy"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs RELAY_TEST_VALKEY"]
+async fn handoff_waits_for_confirmation() {
+    let harness = Harness::new().await;
+    let _relay = harness.start("relay.log").await;
+    let cookie = log_in(&harness.base).await;
+
+    let page = get_page(&harness.base, &handoff_path(RETURN_TO), &cookie).await;
+    assert_eq!(page.status(), StatusCode::OK);
+    let html = page.text().await.unwrap();
+    assert!(
+        html.contains("4567"),
+        "the page doesn't name the port:\n{}",
+        html
+    );
+    assert!(html.contains("action=\"/auth/handoff\""));
+    assert_not_handed_off(&harness.base, &cookie).await;
+
+    // Another site can make the browser post the form, but not with the token.
+    let forged = client()
+        .post(format!("{}/auth/handoff", harness.base))
+        .header("cookie", &cookie)
+        .form(&[("return_to", RETURN_TO), ("nonce", NONCE)])
+        .send()
+        .await
+        .unwrap();
+    assert!(forged.status().is_client_error(), "{}", forged.status());
+    assert_not_handed_off(&harness.base, &cookie).await;
+
+    let code = common::hand_off(&harness.base, &cookie, RETURN_TO, NONCE).await;
+    let (status, key) = post_json(
+        &harness.base,
+        "/api/exchange",
+        serde_json::json!({ "code": code, "nonce": NONCE }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(key["key"].is_string());
+    assert_not_handed_off(&harness.base, &cookie).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs RELAY_TEST_VALKEY"]
+async fn handoff_stays_on_this_machine() {
+    let harness = Harness::new().await;
+    let _relay = harness.start("relay.log").await;
+    let cookie = log_in(&harness.base).await;
+
+    for return_to in [
+        "http://example.com:4567/callback",
+        "https://127.0.0.1:4567/",
+    ] {
+        let page = get_page(&harness.base, &handoff_path(return_to), &cookie).await;
+        assert!(
+            !page.text().await.unwrap().contains("/auth/handoff"),
+            "{} was offered",
+            return_to
+        );
+        let submitted = post_form(
+            &harness.base,
+            "/auth/handoff",
+            &cookie,
+            &[("return_to", return_to), ("nonce", NONCE)],
+        )
+        .await;
+        assert!(
+            !submitted.status().is_redirection(),
+            "{} was accepted",
+            return_to
+        );
+        assert_not_handed_off(&harness.base, &cookie).await;
+    }
+}
@@ -0,0 +1,129 @@
+//! Runs relay against a stand-in osu! server through a login, the ways clients get API keys and tokens, and a token refresh,
+//! and checks that no secret ends up in its logs.
+
+mod common;
+
+use common::{client, get_page, log_in, post_form, post_json, Harness, CLIENT_SECRET, COOKIE_KEY};
+use reqwest::StatusCode;
+
+const NONCE: &str = "client-nonce-7d1e5a3c";
+
+/// Hand the session over to a client on this machine, and trade the exchange code for an API key.
+async fn hand_off(base: &str, cookie: &str) -> (String, String) {
+    let code = common::hand_off(base, cookie, "http://127.0.0.1:1/done", NONCE).await;
+    let body = serde_json::json!({ "code": code, "nonce": NONCE, "label": "handoff" });
+    let (status, key) = post_json(base, "/api/exchange", body.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = post_json(base, "/api/exchange", body).await;
+    assert_eq!(
+        status,
+        StatusCode::NOT_FOUND,
+        "the exchange code was reused"
+    );
+    (code, key["key"].as_str().unwrap().to_owned())
+}
+
+/// Pair a client through a code typed into the pairing page, and poll until it gets its API key.
+async fn pair(base: &str, cookie: &str) -> (String, String) {
+    let (status, challenge) = post_json(base, "/api/pairing", serde_json::json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    let device_code = challenge["device_code"].as_str().unwrap().to_owned();
+    let user_code = challenge["user_code"].as_str().unwrap();
+
+    let (_, pending) = post_json(
+        base,
+        "/api/pairing/poll",
+        serde_json::json!({ "device_code": device_code }),
+    )
+    .await;
+    assert_eq!(pending["status"], "pending");
+    let submitted = post_form(base, "/auth/pair", cookie, &[("user_code", user_code)]).await;
+    assert!(submitted.status().is_redirection());
+    get_page(base, "/auth", cookie).await;
+
+    let (status, complete) = post_json(
+        base,
+        "/api/pairing/poll",
+        serde_json::json!({ "device_code": device_code, "label": "paired" }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(complete["status"], "complete");
+    let key = complete["api_key"]["key"].as_str().unwrap().to_owned();
+    (device_code, key)
+}
+
+async fn fetch_token(base: &str, key: &str) {
+    let response = client()
+        .get(format!("{}/api/token", base))
+        .header("X-Relay-Key", key)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let rejected = client()
+        .get(format!("{}/api/token", base))
+        .header("X-Relay-Key", format!("{}-wrong", key))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(rejected.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs RELAY_TEST_VALKEY"]
+async fn secrets_stay_out_of_logs() {
+    let harness = Harness::new().await;
+    let sessions_before = harness.session_keys();
+    let (cookie, client_secrets) = {
+        let _relay = harness.start("login.log").await;
+        let cookie = log_in(&harness.base).await;
+        let mut client_secrets = Vec::new();
+        for (code, key) in [
+            hand_off(&harness.base, &cookie).await,
+            pair(&harness.base, &cookie).await,
+        ] {
+            fetch_token(&harness.base, &key).await;
+            client_secrets.extend([code, key]);
+        }
+        (cookie, client_secrets)
+    };
+    let new_sessions = harness
+        .session_keys()
+        .into_iter()
+        .filter(|k| !sessions_before.contains(k))
+        .collect::<Vec<_>>();
+    assert!(
+        !new_sessions.is_empty(),
+        "the login didn't create a session"
+    );
+
+    // Tokens expiring within hours are refreshed by the first sweep after a start.
+    let relay = harness.start("refresh.log").await;
+    relay.wait_for("Success: ").await;
+    assert!(
+        harness.issued.grants("refresh_token") > 0,
+        "the refresher didn't refresh the session"
+    );
+    drop(relay);
+
+    let logs = harness.logs(&["login.log", "refresh.log"]);
+    let cookie_value = cookie.split_once('=').unwrap().1.to_owned();
+    let secrets = new_sessions
+        .into_iter()
+        .chain(harness.issued.tokens())
+        .chain(client_secrets)
+        .chain([
+            CLIENT_SECRET.to_owned(),
+            COOKIE_KEY.to_owned(),
+            cookie_value,
+        ]);
+    for secret in secrets {
+        assert!(
+            !logs.contains(&secret),
+            "{:?} was logged in clear text:\n{}",
+            secret,
+            logs
+        );
+    }
+}
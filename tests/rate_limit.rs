@@ -0,0 +1,61 @@
+//! Checks that made-up credentials don't get clients around the rate limits.
+
+mod common;
+
+use common::{client, log_in, Harness};
+use reqwest::StatusCode;
+
+/// Send requests with a new made-up API key every time, until one is turned away. Returns how many got through.
+async fn requests_until_limited(base: &str, cookie: Option<&str>, max: usize) -> Option<usize> {
+    for i in 0..max {
+        let mut request = client()
+            .get(format!("{}/auth", base))
+            .header("X-Relay-Key", format!("relay_made-up-{}", i));
+        if let Some(cookie) = cookie {
+            request = request.header("cookie", cookie);
+        }
+        if request.send().await.unwrap().status() == StatusCode::TOO_MANY_REQUESTS {
+            return Some(i);
+        }
+    }
+    None
+}
+
+async fn session_rejections(base: &str) -> u64 {
+    let metrics = reqwest::get(format!("{}/metrics", base))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    metrics
+        .lines()
+        .find(|line| line.starts_with("relay_rate_limiter_rejections_total{reason=\"session\"}"))
+        .and_then(|line| line.rsplit(' ').next()?.parse().ok())
+        .unwrap_or_default()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs RELAY_TEST_VALKEY"]
+async fn made_up_credentials_are_limited() {
+    let harness = Harness::new().await;
+    harness.set_rate_limit(
+        "{per_ip: {burst: 40, per_second: 5}, per_session: {burst: 5, per_second: 0.1}}",
+    );
+    let relay = harness.start("relay.log").await;
+    let cookie = log_in(&harness.base).await;
+
+    // The session runs out long before the address does, whatever key comes along with its cookie.
+    let passed = requests_until_limited(&harness.base, Some(&cookie), 20).await;
+    assert!(
+        passed.is_some_and(|n| n <= 5),
+        "the session wasn't limited: {:?}\n{}",
+        passed,
+        relay.output()
+    );
+    assert!(session_rejections(&harness.base).await > 0);
+
+    // Without a session, made-up keys only share the address's bucket.
+    let passed = requests_until_limited(&harness.base, None, 200).await;
+    assert!(passed.is_some(), "made-up keys got around the limits");
+}
@@ -0,0 +1,35 @@
+//! Runs the refresher against a stand-in osu! server which turns it down, and checks what happens to the sessions.
+
+mod common;
+
+use common::{log_in, Harness};
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs RELAY_TEST_VALKEY"]
+async fn wrong_client_secret_keeps_sessions() {
+    let harness = Harness::new().await;
+    let sessions_before = harness.session_keys();
+    {
+        let _relay = harness.start("login.log").await;
+        log_in(&harness.base).await;
+    }
+    let session_id = harness
+        .session_keys()
+        .into_iter()
+        .find(|k| !sessions_before.contains(k))
+        .expect("the login didn't create a session");
+
+    harness.set_client_secret("rotated-client-secret");
+    let relay = harness.start("refresh.log").await;
+    relay.wait_for("Success: ").await;
+    assert!(
+        relay.output().contains("Client authentication failed"),
+        "the refresh wasn't turned down:\n{}",
+        relay.output()
+    );
+    assert!(
+        harness.session_keys().contains(&session_id),
+        "the session was removed:\n{}",
+        relay.output()
+    );
+}
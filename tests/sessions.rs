@@ -0,0 +1,45 @@
+mod common;
+
+use common::{form_value, get_page, log_in, post_form, session_cookie, Harness};
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs RELAY_TEST_VALKEY"]
+async fn revoked_device_gets_a_fresh_session() {
+    let harness = Harness::new().await;
+    let _relay = harness.start("relay.log").await;
+    let kept = log_in(&harness.base).await;
+    let revoked = log_in(&harness.base).await;
+
+    // Devices are listed with the same ids on every page, so the revoked one can find itself.
+    let page = get_page(&harness.base, "/auth", &revoked)
+        .await
+        .text()
+        .await
+        .unwrap();
+    let devices = &page[page.find("<h3>Devices</h3>").expect("no device list")..];
+    let device_id = devices
+        .split("<tr>")
+        .filter(|row| row.contains("(this one)"))
+        .find_map(|row| form_value(row, "<form", "id"))
+        .expect("the current device isn't listed");
+
+    let response = post_form(
+        &harness.base,
+        "/auth/devices/revoke",
+        &kept,
+        &[("id", &device_id)],
+    )
+    .await;
+    assert!(response.status().is_redirection());
+
+    // The browser still sends the cookie of the revoked session.
+    let response = get_page(&harness.base, "/auth", &revoked).await;
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    let fresh = session_cookie(&response).expect("no new session cookie");
+    assert_ne!(fresh, revoked);
+    let page = response.text().await.unwrap();
+    assert!(!page.contains("peppy"), "the revoked session still works");
+
+    let health = get_page(&harness.base, "/healthz", &revoked).await;
+    assert_eq!(health.status(), reqwest::StatusCode::OK);
+}